@@ -0,0 +1,289 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verification of the artifacts listed in a signed compos.info file against the files actually
+//! present in an artifacts directory.
+
+use crate::odrefresh::{CURRENT_ARTIFACTS_SUBDIR, ODREFRESH_OUTPUT_ROOT_DIR};
+use anyhow::{bail, Context, Result};
+use odsign_proto::odsign_info::OdsignInfo;
+use protobuf::Message;
+use std::collections::BTreeMap;
+use std::fs::{read_dir, File};
+use std::os::unix::io::AsFd;
+use std::path::{Path, PathBuf};
+
+/// Name of the signed info file written by compsvc alongside the artifacts.
+pub const INFO_FILE: &str = "compos.info";
+
+/// Name of the signature of `INFO_FILE`.
+pub const SIGNATURE_FILE: &str = "compos.info.signature";
+
+const SHA256_HASH_SIZE: usize = 32;
+
+/// The contents of a compos.info file and its signature.
+pub struct SignatureInfo {
+    pub info: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Artifacts whose fs-verity digest matched the signed compos.info, keyed by their path relative
+/// to the artifacts directory, with the digest in hex.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifiedList {
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+/// Verifies that `signature_info` is correctly signed by `public_key`, and that the files under
+/// `artifact_dir` are exactly those listed in it, each with the signed fs-verity digest.
+pub fn verify_artifacts(
+    public_key: &[u8],
+    artifact_dir: &Path,
+    signature_info: &SignatureInfo,
+) -> Result<VerifiedList> {
+    verify_artifacts_with(
+        public_key,
+        artifact_dir,
+        signature_info,
+        compos_verify_native::verify,
+        measure_fsverity,
+    )
+}
+
+fn verify_artifacts_with<V, M>(
+    public_key: &[u8],
+    artifact_dir: &Path,
+    signature_info: &SignatureInfo,
+    verify_signature: V,
+    measure: M,
+) -> Result<VerifiedList>
+where
+    V: Fn(&[u8], &[u8], &[u8]) -> bool,
+    M: Fn(&Path) -> Result<String>,
+{
+    if !verify_signature(public_key, &signature_info.signature, &signature_info.info) {
+        bail!("Signature verification failed");
+    }
+
+    let info = OdsignInfo::parse_from_bytes(&signature_info.info)
+        .context("Failed to parse compos.info")?;
+
+    // Paths in compos.info are where the artifacts will be once activated, not where they are now.
+    let target_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(CURRENT_ARTIFACTS_SUBDIR);
+    let mut expected = BTreeMap::new();
+    for (path, digest) in &info.file_hashes {
+        let relpath = Path::new(path)
+            .strip_prefix(&target_dir)
+            .with_context(|| format!("Unexpected path in compos.info: {}", path))?;
+        expected.insert(relpath.to_owned(), digest.to_lowercase());
+    }
+
+    let mut found = Vec::new();
+    collect_artifacts(artifact_dir, Path::new(""), &mut found)?;
+
+    let mut verified = VerifiedList::default();
+    for relpath in found {
+        let Some(expected_digest) = expected.remove(&relpath) else {
+            bail!("Unexpected file not listed in compos.info: {}", relpath.display());
+        };
+        let path = artifact_dir.join(&relpath);
+        let digest = measure(&path).with_context(|| format!("Measuring {}", path.display()))?;
+        if digest != expected_digest {
+            bail!(
+                "Digest mismatch for {}: expected {}, got {}",
+                relpath.display(),
+                expected_digest,
+                digest
+            );
+        }
+        verified.files.insert(relpath, digest);
+    }
+
+    if let Some(missing) = expected.keys().next() {
+        bail!("Missing file listed in compos.info: {}", missing.display());
+    }
+
+    Ok(verified)
+}
+
+/// Recursively collects the paths, relative to `base_dir`, of all regular files under
+/// `base_dir.join(relpath)`, excluding the info and signature files themselves.
+fn collect_artifacts(base_dir: &Path, relpath: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = base_dir.join(relpath);
+    for entry in read_dir(&dir).with_context(|| format!("Traversing {}", dir.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let entry_relpath = relpath.join(entry.file_name());
+        if file_type.is_dir() {
+            collect_artifacts(base_dir, &entry_relpath, found)?;
+        } else if file_type.is_file() {
+            if entry_relpath != Path::new(INFO_FILE) && entry_relpath != Path::new(SIGNATURE_FILE) {
+                found.push(entry_relpath);
+            }
+        } else {
+            bail!("Unexpected file type in artifacts: {:?}", entry);
+        }
+    }
+    Ok(())
+}
+
+fn measure_fsverity(path: &Path) -> Result<String> {
+    let file = File::open(path)?;
+    let mut buf = [0u8; SHA256_HASH_SIZE];
+    let digest = fsverity::measure(file.as_fd(), &mut buf)?;
+    Ok(hex::encode(digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const PUBLIC_KEY: &[u8] = b"public key";
+
+    // Stand-in for a real signature: the data prefixed by the key.
+    fn fake_sign(data: &[u8]) -> Vec<u8> {
+        [PUBLIC_KEY, data].concat()
+    }
+
+    fn fake_verify(public_key: &[u8], signature: &[u8], data: &[u8]) -> bool {
+        signature == [public_key, data].concat()
+    }
+
+    // Stand-in for the fs-verity digest: the hex encoded file contents.
+    fn fake_measure(path: &Path) -> Result<String> {
+        Ok(hex::encode(fs::read(path)?))
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        signature_info: SignatureInfo,
+    }
+
+    impl Fixture {
+        /// Writes the given artifacts and a compos.info listing them, signed with the fake key.
+        fn new(artifacts: &[(&str, &[u8])]) -> Result<Self> {
+            let dir = TempDir::new()?;
+            let target_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(CURRENT_ARTIFACTS_SUBDIR);
+            let mut info = OdsignInfo::new();
+            for (name, contents) in artifacts {
+                let path = dir.path().join(name);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(&path, contents)?;
+                let target_path = target_dir.join(name).to_str().unwrap().to_owned();
+                info.file_hashes.insert(target_path, hex::encode(contents));
+            }
+            let info = info.write_to_bytes()?;
+            let signature = fake_sign(&info);
+            fs::write(dir.path().join(INFO_FILE), &info)?;
+            fs::write(dir.path().join(SIGNATURE_FILE), &signature)?;
+            Ok(Self { dir, signature_info: SignatureInfo { info, signature } })
+        }
+
+        fn verify(&self) -> Result<VerifiedList> {
+            verify_artifacts_with(
+                PUBLIC_KEY,
+                self.dir.path(),
+                &self.signature_info,
+                fake_verify,
+                fake_measure,
+            )
+        }
+    }
+
+    const ARTIFACTS: &[(&str, &[u8])] = &[
+        ("arm64/boot.oat", b"boot oat"),
+        ("arm64/boot.vdex", b"boot vdex"),
+        ("system@framework@services.jar@classes.odex", b"services odex"),
+    ];
+
+    #[test]
+    fn verify_valid_artifacts() -> Result<()> {
+        let fixture = Fixture::new(ARTIFACTS)?;
+        let verified = fixture.verify()?;
+
+        let names: Vec<_> = verified.files.keys().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            ["arm64/boot.oat", "arm64/boot.vdex", "system@framework@services.jar@classes.odex"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_empty_artifacts() -> Result<()> {
+        let fixture = Fixture::new(&[])?;
+        assert_eq!(fixture.verify()?, VerifiedList::default());
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_artifact_is_reported() -> Result<()> {
+        let fixture = Fixture::new(ARTIFACTS)?;
+        fs::write(fixture.dir.path().join("arm64/boot.vdex"), b"tampered")?;
+
+        let error = fixture.verify().unwrap_err().to_string();
+        assert!(error.contains("Digest mismatch for arm64/boot.vdex"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn extra_artifact_is_rejected() -> Result<()> {
+        let fixture = Fixture::new(ARTIFACTS)?;
+        fs::write(fixture.dir.path().join("arm64/extra.oat"), b"extra")?;
+
+        let error = fixture.verify().unwrap_err().to_string();
+        assert!(error.contains("Unexpected file not listed in compos.info: arm64/extra.oat"));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_artifact_is_rejected() -> Result<()> {
+        let fixture = Fixture::new(ARTIFACTS)?;
+        fs::remove_file(fixture.dir.path().join("arm64/boot.oat"))?;
+
+        let error = fixture.verify().unwrap_err().to_string();
+        assert!(error.contains("Missing file listed in compos.info: arm64/boot.oat"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_info_is_rejected() -> Result<()> {
+        let mut fixture = Fixture::new(ARTIFACTS)?;
+        let last = fixture.signature_info.info.len() - 1;
+        fixture.signature_info.info[last] ^= 1;
+
+        let error = fixture.verify().unwrap_err().to_string();
+        assert_eq!(error, "Signature verification failed");
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let fixture = Fixture::new(ARTIFACTS).unwrap();
+        let result = verify_artifacts_with(
+            b"other key",
+            fixture.dir.path(),
+            &fixture.signature_info,
+            fake_verify,
+            fake_measure,
+        );
+
+        let error = result.unwrap_err().to_string();
+        assert_eq!(error, "Signature verification failed");
+    }
+}
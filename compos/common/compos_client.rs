@@ -19,7 +19,7 @@
 use crate::timeouts::TIMEOUTS;
 use crate::{
     get_vm_config_path, BUILD_MANIFEST_APK_PATH, BUILD_MANIFEST_SYSTEM_EXT_APK_PATH,
-    COMPOS_APEX_ROOT, COMPOS_VSOCK_PORT,
+    COMPOS_APEX_ROOT, COMPOS_HEALTH_VSOCK_PORT, COMPOS_VSOCK_PORT,
 };
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     CpuTopology::CpuTopology,
@@ -32,25 +32,76 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 };
 use anyhow::{anyhow, bail, Context, Result};
 use binder::{ParcelFileDescriptor, Strong};
-use compos_aidl_interface::aidl::com::android::compos::ICompOsService::ICompOsService;
+use compos_aidl_interface::aidl::com::android::compos::{
+    ICompOsHealth::ICompOsHealth, ICompOsService::ICompOsService,
+};
 use glob::glob;
 use log::{info, warn};
 use platformproperties::hypervisorproperties;
-use std::fs::File;
+use rpcbinder::RpcSession;
+use std::fs::{self, File};
+use std::num::NonZeroU32;
+use std::os::unix::io::IntoRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use vmclient::{DeathReason, ErrorCode, VmInstance, VmWaitError};
 
 /// This owns an instance of the CompOS VM.
-pub struct ComposClient(VmInstance);
+pub struct ComposClient {
+    // Declared before the VM so that it stops, and releases the VM, before the VM is dropped.
+    keepalive: Option<Keepalive>,
+    instance: Arc<VmInstance>,
+}
+
+/// How long the keepalive waits between two health checks of the VM.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of health checks in a row that the VM may fail before the keepalive stops it.
+const KEEPALIVE_MAX_FAILED_CHECKS: u32 = 3;
+
+/// The maximum number of vCPUs given to the VM by [`VmCpuTopology::default_for_compilation`].
+/// Beyond this, the VM would compete with the host for its CPUs for little gain.
+pub const MAX_DEFAULT_CPU_COUNT: u32 = 8;
+
+/// Capacity of each CPU of the host, relative to the biggest one. Missing on homogeneous hosts.
+const HOST_CPU_CAPACITY_GLOB: &str = "/sys/devices/system/cpu/cpu[0-9]*/cpu_capacity";
 
 /// CPU topology configuration for a virtual machine.
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub enum VmCpuTopology {
     /// Run VM with 1 vCPU only.
     #[default]
     OneCpu,
     /// Run VM vCPU topology matching that of the host.
     MatchHost,
+    /// Run VM with the given number of vCPUs.
+    Count(NonZeroU32),
+    /// Run VM with as many vCPUs as the host has big cores, i.e. CPUs with the highest capacity.
+    MatchHostBigCores,
+}
+
+impl VmCpuTopology {
+    /// Returns the topology to use for compilation: that of the host or, if the host has more
+    /// than [`MAX_DEFAULT_CPU_COUNT`] CPUs, that many vCPUs.
+    pub fn default_for_compilation() -> Result<Self> {
+        Ok(default_compilation_topology(host_cpu_count()?))
+    }
+
+    /// Returns the number of vCPUs that the VM gets with this topology.
+    pub fn cpu_count(&self) -> Result<NonZeroU32> {
+        match self {
+            Self::OneCpu => Ok(NonZeroU32::MIN),
+            Self::MatchHost => host_cpu_count(),
+            Self::Count(count) => Ok(*count),
+            Self::MatchHostBigCores => match big_core_count(&host_cpu_capacities()?) {
+                Some(count) => Ok(count),
+                None => host_cpu_count(), // All CPUs are equal.
+            },
+        }
+    }
 }
 
 /// Parameters to be used when creating a virtual machine instance.
@@ -117,9 +168,13 @@ impl ComposClient {
 
         let debug_level = if parameters.debug_mode { DebugLevel::FULL } else { DebugLevel::NONE };
 
-        let cpu_topology = match parameters.cpu_topology {
-            VmCpuTopology::OneCpu => CpuTopology::ONE_CPU,
-            VmCpuTopology::MatchHost => CpuTopology::MATCH_HOST,
+        let (cpu_topology, cpu_count) = match parameters.cpu_topology {
+            VmCpuTopology::OneCpu => (CpuTopology::ONE_CPU, 0),
+            VmCpuTopology::MatchHost => (CpuTopology::MATCH_HOST, 0),
+            VmCpuTopology::Count(_) | VmCpuTopology::MatchHostBigCores => {
+                let cpu_count = parameters.cpu_topology.cpu_count()?;
+                (CpuTopology::CUSTOM, cpu_count.get().try_into().context("Invalid CPU count")?)
+            }
         };
 
         // The CompOS VM doesn't need to be updatable (by design it should run exactly twice,
@@ -139,6 +194,7 @@ impl ComposClient {
             protectedVm: true,
             memoryMib: parameters.memory_mib.unwrap_or(0), // 0 means use the default
             cpuTopology: cpu_topology,
+            cpuCount: cpu_count,
             customConfig: custom_config,
             ..Default::default()
         });
@@ -170,17 +226,35 @@ impl ComposClient {
         }
         ready?;
 
-        Ok(Self(instance))
+        Ok(Self { keepalive: None, instance: Arc::new(instance) })
     }
 
     /// Create and return an RPC Binder connection to the Comp OS service in the VM.
     pub fn connect_service(&self) -> Result<Strong<dyn ICompOsService>> {
-        self.0.connect_service(COMPOS_VSOCK_PORT).context("Connecting to CompOS service")
+        self.instance.connect_service(COMPOS_VSOCK_PORT).context("Connecting to CompOS service")
+    }
+
+    /// Returns whether the Comp OS service in the VM reports its health within `deadline`. This
+    /// uses [`COMPOS_HEALTH_VSOCK_PORT`], so it doesn't wait for any compilation in progress.
+    pub fn is_healthy(&self, deadline: Duration) -> bool {
+        is_healthy(&self.instance, deadline)
+    }
+
+    /// Starts checking the health of the VM in the background, until it is shut down. If the VM
+    /// fails [`KEEPALIVE_MAX_FAILED_CHECKS`] checks in a row, it is considered hung and stopped.
+    /// That makes any pending call to the service fail, rather than leaving the caller waiting for
+    /// its own timeout.
+    pub fn start_keepalive(&mut self) {
+        if self.keepalive.is_none() {
+            self.keepalive = Some(Keepalive::start(self.instance.clone()));
+        }
     }
 
     /// Shut down the VM cleanly, by sending a quit request to the service, giving time for any
     /// relevant logs to be written.
-    pub fn shutdown(self, service: Strong<dyn ICompOsService>) {
+    pub fn shutdown(mut self, service: Strong<dyn ICompOsService>) {
+        // The VM stops answering health checks as it shuts down.
+        drop(self.keepalive.take());
         info!("Requesting CompOS VM to shutdown");
         let _ignored = service.quit(); // If this fails, the VM is probably dying anyway
         self.wait_for_shutdown();
@@ -191,7 +265,7 @@ impl ComposClient {
     /// This should only be called when the instance has been requested to quit, or we believe that
     /// it is already in the process of exiting due to some failure.
     fn wait_for_shutdown(self) {
-        let death_reason = self.0.wait_for_death_with_timeout(TIMEOUTS.vm_max_time_to_exit);
+        let death_reason = self.instance.wait_for_death_with_timeout(TIMEOUTS.vm_max_time_to_exit);
         match death_reason {
             Some(DeathReason::Shutdown) => info!("VM has exited normally"),
             Some(reason) => warn!("VM died with reason {:?}", reason),
@@ -200,6 +274,102 @@ impl ComposClient {
     }
 }
 
+/// Returns whether the Comp OS service in `instance` reports its health within `deadline`.
+fn is_healthy(instance: &VmInstance, deadline: Duration) -> bool {
+    // The connection is set up, and the call made, on a separate thread that holds only the vsock.
+    // A binder call can't be cancelled, so that thread is left behind if the deadline passes, but
+    // it doesn't keep the VM alive and fails once the VM is stopped.
+    let vsock = match instance.vm.connectVsock(COMPOS_HEALTH_VSOCK_PORT as i32) {
+        Ok(vsock) => vsock,
+        Err(e) => {
+            warn!("Failed to connect to CompOS health service: {:?}", e);
+            return false;
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut vsock = Some(vsock);
+        let health = RpcSession::new()
+            .setup_preconnected_client::<dyn ICompOsHealth>(|| {
+                // Ownership of the fd is transferred to binder
+                vsock.take().map(IntoRawFd::into_raw_fd)
+            })
+            .context("Connecting to CompOS health service")
+            .and_then(|service| service.health().context("Getting health"));
+        let _ignored = sender.send(health); // The receiver may have given up.
+    });
+    match receiver.recv_timeout(deadline) {
+        Ok(Ok(health)) => {
+            info!("CompOS VM health: {:?}", health);
+            true
+        }
+        Ok(Err(e)) => {
+            warn!("Failed to get CompOS VM health: {:?}", e);
+            false
+        }
+        Err(_) => {
+            warn!("CompOS VM failed to report its health within {:?}", deadline);
+            false
+        }
+    }
+}
+
+/// Periodically checks the health of a VM, on its own thread, and stops the VM if it fails too many
+/// checks in a row.
+struct Keepalive {
+    // Dropping this tells the thread to exit.
+    stop_sender: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Keepalive {
+    fn start(instance: Arc<VmInstance>) -> Self {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let mut failed_checks = FailedChecks::default();
+            while stop_receiver.recv_timeout(KEEPALIVE_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+                let healthy = is_healthy(&instance, TIMEOUTS.vm_max_time_to_report_health);
+                if failed_checks.record(healthy) {
+                    warn!(
+                        "CompOS VM failed {} health checks in a row, stopping it",
+                        failed_checks.0
+                    );
+                    if let Err(e) = instance.vm.stop() {
+                        warn!("Failed to stop CompOS VM: {:?}", e);
+                    }
+                    break;
+                }
+            }
+        });
+        Self { stop_sender: Some(stop_sender), thread: Some(thread) }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(thread) = self.thread.take() {
+            // This waits for at most one health check, which has a deadline.
+            if thread.join().is_err() {
+                warn!("CompOS VM keepalive panicked");
+            }
+        }
+    }
+}
+
+/// Number of health checks in a row that a VM failed.
+#[derive(Default)]
+struct FailedChecks(u32);
+
+impl FailedChecks {
+    /// Records the result of a health check, and returns whether the VM has now failed
+    /// [`KEEPALIVE_MAX_FAILED_CHECKS`] checks in a row.
+    fn record(&mut self, healthy: bool) -> bool {
+        self.0 = if healthy { 0 } else { self.0 + 1 };
+        self.0 >= KEEPALIVE_MAX_FAILED_CHECKS
+    }
+}
+
 fn locate_config_apk(apex_dir: &Path) -> Result<PathBuf> {
     // Our config APK will be in a directory under app, but the name of the directory is at the
     // discretion of the build system. So just look in each sub-directory until we find it.
@@ -239,6 +409,44 @@ fn prepare_idsig(
     Ok(idsig_fd)
 }
 
+/// Returns the default topology for compilation on a host with `host_cpu_count` CPUs.
+fn default_compilation_topology(host_cpu_count: NonZeroU32) -> VmCpuTopology {
+    // Only fall back to a plain vCPU count when it has to be capped, as that loses the topology.
+    if host_cpu_count.get() <= MAX_DEFAULT_CPU_COUNT {
+        VmCpuTopology::MatchHost
+    } else {
+        VmCpuTopology::Count(NonZeroU32::new(MAX_DEFAULT_CPU_COUNT).unwrap())
+    }
+}
+
+/// Returns the number of CPUs with the highest capacity, or `None` if `capacities` is empty.
+fn big_core_count(capacities: &[u32]) -> Option<NonZeroU32> {
+    let max_capacity = capacities.iter().max()?;
+    let count = capacities.iter().filter(|capacity| *capacity == max_capacity).count();
+    NonZeroU32::new(count.try_into().ok()?)
+}
+
+fn host_cpu_count() -> Result<NonZeroU32> {
+    // SAFETY: sysconf has no memory safety implications.
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    count
+        .try_into()
+        .ok()
+        .and_then(NonZeroU32::new)
+        .ok_or_else(|| anyhow!("Failed to determine the number of host CPUs"))
+}
+
+fn host_cpu_capacities() -> Result<Vec<u32>> {
+    glob(HOST_CPU_CAPACITY_GLOB)
+        .context("failed to glob")?
+        .map(|path| {
+            let path = path?;
+            let capacity = fs::read_to_string(&path)?;
+            capacity.trim().parse().with_context(|| format!("Invalid capacity in {:?}", path))
+        })
+        .collect()
+}
+
 struct Callback {}
 impl vmclient::VmCallback for Callback {
     fn on_payload_started(&self, cid: i32) {
@@ -261,3 +469,74 @@ impl vmclient::VmCallback for Callback {
         log::warn!("VM died, cid = {}, reason = {:?}", cid, death_reason);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    #[test]
+    fn default_compilation_topology_matches_host() {
+        for host_cpu_count in [1, 2, 6, MAX_DEFAULT_CPU_COUNT] {
+            assert_eq!(
+                default_compilation_topology(count(host_cpu_count)),
+                VmCpuTopology::MatchHost,
+                "{host_cpu_count} host CPUs"
+            );
+        }
+    }
+
+    #[test]
+    fn default_compilation_topology_is_capped() {
+        for host_cpu_count in [MAX_DEFAULT_CPU_COUNT + 1, 12, 128] {
+            assert_eq!(
+                default_compilation_topology(count(host_cpu_count)),
+                VmCpuTopology::Count(count(MAX_DEFAULT_CPU_COUNT)),
+                "{host_cpu_count} host CPUs"
+            );
+        }
+    }
+
+    #[test]
+    fn big_core_count_of_heterogeneous_host() {
+        assert_eq!(big_core_count(&[160, 160, 160, 160, 512, 512, 512, 1024]), Some(count(1)));
+        assert_eq!(big_core_count(&[1024, 1024, 378, 378, 378, 378]), Some(count(2)));
+    }
+
+    #[test]
+    fn big_core_count_of_homogeneous_host() {
+        assert_eq!(big_core_count(&[1024; 8]), Some(count(8)));
+        assert_eq!(big_core_count(&[]), None);
+    }
+
+    #[test]
+    fn keepalive_gives_up_after_failed_checks_in_a_row() {
+        let mut failed_checks = FailedChecks::default();
+        for _ in 1..KEEPALIVE_MAX_FAILED_CHECKS {
+            assert!(!failed_checks.record(false));
+        }
+        assert!(failed_checks.record(false));
+    }
+
+    #[test]
+    fn healthy_check_resets_failed_checks() {
+        let mut failed_checks = FailedChecks::default();
+        for _ in 1..KEEPALIVE_MAX_FAILED_CHECKS {
+            assert!(!failed_checks.record(false));
+        }
+        assert!(!failed_checks.record(true));
+        for _ in 1..KEEPALIVE_MAX_FAILED_CHECKS {
+            assert!(!failed_checks.record(false));
+        }
+    }
+
+    #[test]
+    fn fixed_topologies_cpu_count() -> Result<()> {
+        assert_eq!(VmCpuTopology::OneCpu.cpu_count()?, count(1));
+        assert_eq!(VmCpuTopology::Count(count(4)).cpu_count()?, count(4));
+        Ok(())
+    }
+}
@@ -16,6 +16,7 @@
 
 //! Common items used by CompOS server and/or clients
 
+pub mod artifacts;
 pub mod binder;
 pub mod compos_client;
 pub mod odrefresh;
@@ -25,6 +26,10 @@ pub mod timeouts;
 /// future port range (if happens) that microdroid may reserve for system components.
 pub const COMPOS_VSOCK_PORT: u32 = 6432;
 
+/// VSock port on which the CompOS server also serves its health checks, on a separate thread so
+/// that they are answered while a task is in progress on [`COMPOS_VSOCK_PORT`].
+pub const COMPOS_HEALTH_VSOCK_PORT: u32 = 6433;
+
 /// The root directory where the CompOS APEX is mounted (read only).
 pub const COMPOS_APEX_ROOT: &str = "/apex/com.android.compos";
 
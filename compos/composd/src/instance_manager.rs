@@ -79,9 +79,9 @@ impl InstanceManager {
 
 fn new_vm_parameters() -> Result<VmParameters> {
     // By default, dex2oat starts as many threads as there are CPUs. This can be overridden with
-    // a system property. Start the VM with all CPUs and assume the guest will start a suitable
-    // number of dex2oat threads.
-    let cpu_topology = VmCpuTopology::MatchHost;
+    // a system property. Start the VM with as many CPUs as the host has, up to a maximum, and
+    // assume the guest will start a suitable number of dex2oat threads.
+    let cpu_topology = VmCpuTopology::default_for_compilation()?;
     let memory_mib = Some(compos_memory_mib()?);
     Ok(VmParameters { cpu_topology, memory_mib, ..Default::default() })
 }
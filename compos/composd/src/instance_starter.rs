@@ -30,6 +30,7 @@ use compos_common::{
 };
 use log::info;
 use std::fs;
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -41,6 +42,7 @@ pub struct CompOsInstance {
     lazy_service_guard: LazyServiceGuard,
     // Keep this alive as long as we are
     instance_tracker: Arc<()>,
+    cpu_count: NonZeroU32,
 }
 
 impl CompOsInstance {
@@ -48,6 +50,11 @@ impl CompOsInstance {
         self.service.clone()
     }
 
+    /// Returns the number of vCPUs of the VM.
+    pub fn cpu_count(&self) -> NonZeroU32 {
+        self.cpu_count
+    }
+
     /// Returns an Arc that this instance holds a strong reference to as long as it exists. This
     /// can be used to determine when the instance has been dropped.
     pub fn get_instance_tracker(&self) -> &Arc<()> {
@@ -141,7 +148,9 @@ impl InstanceStarter {
             .write(true)
             .open(&self.instance_image)
             .context("Failed to open instance image")?;
-        let vm_instance = ComposClient::start(
+        let cpu_count = self.vm_parameters.cpu_topology.cpu_count()?;
+        info!("Starting {} CompOS VM with {} vCPUs", self.instance_name, cpu_count);
+        let mut vm_instance = ComposClient::start(
             virtualization_service,
             instance_id,
             instance_image,
@@ -152,11 +161,14 @@ impl InstanceStarter {
         )
         .context("Starting VM")?;
         let service = vm_instance.connect_service().context("Connecting to CompOS")?;
+        // Stop the VM if it hangs, rather than wait for the compilation to time out.
+        vm_instance.start_keepalive();
         Ok(CompOsInstance {
             vm_instance,
             service,
             lazy_service_guard: Default::default(),
             instance_tracker: Default::default(),
+            cpu_count,
         })
     }
 
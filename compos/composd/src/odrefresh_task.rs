@@ -37,12 +37,14 @@ use odsign_proto::odsign_info::OdsignInfo;
 use protobuf::Message;
 use rustutils::system_properties;
 use std::fs::{remove_dir_all, File, OpenOptions};
+use std::num::NonZeroU32;
 use std::os::fd::AsFd;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct OdrefreshTask {
@@ -83,10 +85,17 @@ impl OdrefreshTask {
         callback: &Strong<dyn ICompilationTaskCallback>,
     ) -> Result<OdrefreshTask> {
         let service = comp_os.get_service();
+        let cpu_count = comp_os.cpu_count();
         let task = RunningTask { comp_os, callback: callback.clone() };
         let task = OdrefreshTask { running_task: Arc::new(Mutex::new(Some(task))) };
 
-        task.clone().start_thread(service, compilation_mode, target_dir_name);
+        // Lets the client record the size of the VM along with the outcome of the compilation.
+        let vcpu_count = cpu_count.get().try_into().context("Invalid vCPU count")?;
+        if let Err(e) = callback.onVmStarted(vcpu_count) {
+            warn!("Failed to deliver callback: {:?}", e);
+        }
+
+        task.clone().start_thread(service, compilation_mode, target_dir_name, cpu_count);
 
         Ok(task)
     }
@@ -96,9 +105,13 @@ impl OdrefreshTask {
         service: Strong<dyn ICompOsService>,
         compilation_mode: CompilationMode,
         target_dir_name: String,
+        cpu_count: NonZeroU32,
     ) {
         thread::spawn(move || {
+            let start_time = Instant::now();
             let exit_code = run_in_vm(service, compilation_mode, &target_dir_name);
+            // Allows correlating the compilation time with the size of the VM.
+            info!("Compilation ran for {:?} with {} vCPUs", start_time.elapsed(), cpu_count);
 
             let task = self.take();
             // We don't do the callback if cancel has already happened.
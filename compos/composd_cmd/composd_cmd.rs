@@ -81,6 +81,11 @@ enum Outcome {
 impl Interface for Callback {}
 
 impl ICompilationTaskCallback for Callback {
+    fn onVmStarted(&self, vcpu_count: i32) -> BinderResult<()> {
+        println!("CompOS VM started with {} vCPUs", vcpu_count);
+        Ok(())
+    }
+
     fn onSuccess(&self) -> BinderResult<()> {
         self.0.set_outcome(Outcome::Succeeded);
         Ok(())
@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Health reporting of compsvc: statistics about the tasks it runs and the ICompOsHealth service
+//! through which they are read. Unlike compsvc itself, this doesn't depend on Microdroid.
+
+use anyhow::{bail, Result};
+use binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
+use compos_aidl_interface::aidl::com::android::compos::ICompOsHealth::{
+    BnCompOsHealth, HealthInfo::HealthInfo, ICompOsHealth,
+};
+use compos_common::binder::to_binder_result;
+use compos_common::odrefresh::ExitCode;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+/// Constructs a binder object that implements ICompOsHealth, reporting the given `task_stats`.
+pub fn new_binder(task_stats: Arc<TaskStats>) -> Strong<dyn ICompOsHealth> {
+    BnCompOsHealth::new_binder(HealthService { task_stats }, BinderFeatures::default())
+}
+
+struct HealthService {
+    task_stats: Arc<TaskStats>,
+}
+
+impl Interface for HealthService {}
+
+impl ICompOsHealth for HealthService {
+    fn health(&self) -> BinderResult<HealthInfo> {
+        to_binder_result(self.task_stats.health_info())
+    }
+}
+
+/// Counts the tasks run by the service, using atomics so that they can be read without waiting for
+/// a task in progress.
+#[derive(Default)]
+pub struct TaskStats {
+    executed: AtomicI32,
+    failed: AtomicI32,
+    /// Number of tasks currently running.
+    in_progress: AtomicI32,
+}
+
+impl TaskStats {
+    /// Runs `task`, an invocation of odrefresh, and records whether it succeeded. Any exit code
+    /// other than `Okay` or `CompilationSuccess` means that odrefresh failed to do its job, even
+    /// though the task itself didn't return an error.
+    pub fn track_odrefresh(&self, task: impl FnOnce() -> Result<ExitCode>) -> Result<ExitCode> {
+        self.in_progress.fetch_add(1, Ordering::SeqCst);
+        let result = task();
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+
+        self.executed.fetch_add(1, Ordering::SeqCst);
+        if !matches!(result, Ok(ExitCode::Okay | ExitCode::CompilationSuccess)) {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Returns the health of the service, combining the task counters with the state of the VM.
+    pub fn health_info(&self) -> Result<HealthInfo> {
+        let mut info = MaybeUninit::<libc::sysinfo>::uninit();
+        // SAFETY: sysinfo only writes to the struct we pass it, and we check for errors before
+        // reading it.
+        if unsafe { libc::sysinfo(info.as_mut_ptr()) } != 0 {
+            bail!("sysinfo failed: {}", io::Error::last_os_error());
+        }
+        // SAFETY: sysinfo succeeded, so the struct has been initialized.
+        let info = unsafe { info.assume_init() };
+
+        Ok(HealthInfo {
+            uptimeMillis: i64::from(info.uptime) * 1000,
+            freeMemoryBytes: (info.freeram as i64).saturating_mul(info.mem_unit.into()),
+            ..self.counters()
+        })
+    }
+
+    fn counters(&self) -> HealthInfo {
+        HealthInfo {
+            tasksExecuted: self.executed.load(Ordering::SeqCst),
+            tasksFailed: self.failed.load(Ordering::SeqCst),
+            taskInProgress: self.in_progress.load(Ordering::SeqCst) > 0,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn counters_start_at_zero() {
+        let stats = TaskStats::default();
+        let counters = stats.counters();
+        assert_eq!(counters.tasksExecuted, 0);
+        assert_eq!(counters.tasksFailed, 0);
+        assert!(!counters.taskInProgress);
+    }
+
+    #[test]
+    fn counters_move_after_tasks() {
+        let stats = TaskStats::default();
+
+        let result = stats.track_odrefresh(|| {
+            // The task is reported while it runs.
+            assert!(stats.counters().taskInProgress);
+            Ok(ExitCode::CompilationSuccess)
+        });
+        assert_eq!(result.unwrap(), ExitCode::CompilationSuccess);
+        let counters = stats.counters();
+        assert_eq!(counters.tasksExecuted, 1);
+        assert_eq!(counters.tasksFailed, 0);
+        assert!(!counters.taskInProgress);
+
+        let result = stats.track_odrefresh(|| bail!("fake failure"));
+        assert!(result.is_err());
+        let counters = stats.counters();
+        assert_eq!(counters.tasksExecuted, 2);
+        assert_eq!(counters.tasksFailed, 1);
+        assert!(!counters.taskInProgress);
+    }
+
+    #[test]
+    fn unsuccessful_exit_codes_are_failures() {
+        let stats = TaskStats::default();
+
+        stats.track_odrefresh(|| Ok(ExitCode::Okay)).unwrap();
+        assert_eq!(stats.counters().tasksFailed, 0);
+
+        for (i, exit_code) in
+            [ExitCode::CompilationRequired, ExitCode::CompilationFailed, ExitCode::CleanupFailed]
+                .into_iter()
+                .enumerate()
+        {
+            stats.track_odrefresh(|| Ok(exit_code)).unwrap();
+            assert_eq!(stats.counters().tasksFailed, i as i32 + 1);
+        }
+        assert_eq!(stats.counters().tasksExecuted, 4);
+    }
+
+    #[test]
+    fn health_is_reported_while_task_is_blocked() {
+        let stats = Arc::new(TaskStats::default());
+        let service = new_binder(stats.clone());
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let task = thread::spawn(move || {
+            stats.track_odrefresh(|| {
+                started_sender.send(()).unwrap();
+                released.recv().unwrap();
+                Ok(ExitCode::CompilationSuccess)
+            })
+        });
+        started.recv().unwrap();
+
+        // The task is still blocked, but its progress can be read.
+        let health = service.health().unwrap();
+        assert!(health.taskInProgress);
+        assert_eq!(health.tasksExecuted, 0);
+
+        release.send(()).unwrap();
+        assert_eq!(task.join().unwrap().unwrap(), ExitCode::CompilationSuccess);
+        let health = service.health().unwrap();
+        assert!(!health.taskInProgress);
+        assert_eq!(health.tasksExecuted, 1);
+    }
+
+    #[test]
+    fn health_reports_vm_state() {
+        let health = new_binder(Arc::default()).health().unwrap();
+        assert!(health.uptimeMillis > 0);
+        assert!(health.freeMemoryBytes > 0);
+    }
+}
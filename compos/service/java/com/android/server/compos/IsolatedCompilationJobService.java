@@ -217,6 +217,11 @@ public class IsolatedCompilationJobService extends JobService {
             onCompletion(false, IsolatedCompilationMetrics.RESULT_COMPOSD_DIED);
         }
 
+        @Override
+        public void onVmStarted(int vcpuCount) {
+            mMetrics.onVmStarted(vcpuCount);
+        }
+
         @Override
         public void onSuccess() {
             onCompletion(true, IsolatedCompilationMetrics.RESULT_SUCCESS);
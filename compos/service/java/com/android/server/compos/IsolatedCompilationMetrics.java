@@ -86,6 +86,7 @@ class IsolatedCompilationMetrics {
             ArtStatsLog.ISOLATED_COMPILATION_SCHEDULED__SCHEDULING_RESULT__SCHEDULING_SUCCESS;
 
     private long mCompilationStartTimeMs = 0;
+    private int mVcpuCount = 0;
 
     public static void onCompilationScheduled(@ScheduleJobResult int result) {
         ArtStatsLog.write(ArtStatsLog.ISOLATED_COMPILATION_SCHEDULED, result);
@@ -96,6 +97,10 @@ class IsolatedCompilationMetrics {
         mCompilationStartTimeMs = SystemClock.elapsedRealtime();
     }
 
+    public void onVmStarted(int vcpuCount) {
+        mVcpuCount = vcpuCount;
+    }
+
     public void onCompilationJobCanceled(@JobParameters.StopReason int jobStopReason) {
         statsLogPostCompilation(RESULT_JOB_CANCELED, jobStopReason);
     }
@@ -110,10 +115,13 @@ class IsolatedCompilationMetrics {
         long compilationTime = mCompilationStartTimeMs == 0 ? -1
                 : SystemClock.elapsedRealtime() - mCompilationStartTimeMs;
         mCompilationStartTimeMs = 0;
+        // 0 if the VM never started. Only logged, as the atom has no field for it.
+        int vcpuCount = mVcpuCount;
+        mVcpuCount = 0;
 
         ArtStatsLog.write(ArtStatsLog.ISOLATED_COMPILATION_ENDED, compilationTime,
                 result, jobStopReason);
         Log.i(TAG, "ISOLATED_COMPILATION_ENDED: " + result + ", " + compilationTime
-                + ", " + jobStopReason);
+                + ", " + jobStopReason + ", " + vcpuCount);
     }
 }
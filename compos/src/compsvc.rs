@@ -25,7 +25,7 @@ use std::default::Default;
 use std::fs::read_dir;
 use std::iter::zip;
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 use crate::artifact_signer::ArtifactSigner;
 use crate::compilation::odrefresh;
@@ -41,13 +41,16 @@ use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
 };
 use compos_common::binder::to_binder_result;
 use compos_common::odrefresh::{is_system_property_interesting, ODREFRESH_PATH};
+use compos_health::TaskStats;
 use rpcbinder::RpcSession;
 
-/// Constructs a binder object that implements ICompOsService.
-pub fn new_binder() -> Result<Strong<dyn ICompOsService>> {
+/// Constructs a binder object that implements ICompOsService, recording the tasks it runs in
+/// `task_stats`.
+pub fn new_binder(task_stats: Arc<TaskStats>) -> Result<Strong<dyn ICompOsService>> {
     let service = CompOsService {
         odrefresh_path: PathBuf::from(ODREFRESH_PATH),
         initialized: RwLock::new(None),
+        task_stats,
     };
     Ok(BnCompOsService::new_binder(service, BinderFeatures::default()))
 }
@@ -60,6 +63,9 @@ struct CompOsService {
     ///  * Some(true): initialized successfully
     ///  * Some(false): failed to initialize
     initialized: RwLock<Option<bool>>,
+
+    /// Statistics about the tasks run so far, reported by the health service.
+    task_stats: Arc<TaskStats>,
 }
 
 impl Interface for CompOsService {}
@@ -127,15 +133,19 @@ impl CompOsService {
         let authfs_service: Strong<dyn IAuthFsService> = RpcSession::new()
             .setup_unix_domain_client(AUTHFS_SERVICE_SOCKET_NAME)
             .with_context(|| format!("Failed to connect to {}", AUTHFS_SERVICE_SOCKET_NAME))?;
-        let exit_code = odrefresh(&self.odrefresh_path, args, authfs_service, |output_dir| {
-            // authfs only shows us the files we created, so it's ok to just sign everything
-            // under the output directory.
-            let mut artifact_signer = ArtifactSigner::new(&output_dir);
-            add_artifacts(&output_dir, &mut artifact_signer)?;
-
-            artifact_signer.write_info_and_signature(&output_dir.join("compos.info"))
-        })
-        .context("odrefresh failed")?;
+        let exit_code = self
+            .task_stats
+            .track_odrefresh(|| {
+                odrefresh(&self.odrefresh_path, args, authfs_service, |output_dir| {
+                    // authfs only shows us the files we created, so it's ok to just sign
+                    // everything under the output directory.
+                    let mut artifact_signer = ArtifactSigner::new(&output_dir);
+                    add_artifacts(&output_dir, &mut artifact_signer)?;
+
+                    artifact_signer.write_info_and_signature(&output_dir.join("compos.info"))
+                })
+            })
+            .context("odrefresh failed")?;
         Ok(exit_code as i8)
     }
 }
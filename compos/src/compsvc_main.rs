@@ -22,13 +22,16 @@ mod compos_key;
 mod compsvc;
 mod fsverity;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use binder::unstable_api::AsNative;
-use compos_common::COMPOS_VSOCK_PORT;
+use compos_common::{COMPOS_HEALTH_VSOCK_PORT, COMPOS_VSOCK_PORT};
+use compos_health::TaskStats;
 use log::{debug, error};
+use rpcbinder::RpcServer;
 use std::os::raw::c_void;
 use std::panic;
 use std::ptr;
+use std::sync::Arc;
 use vm_payload_bindgen::{AIBinder, AVmPayload_notifyPayloadReady, AVmPayload_runVsockRpcServer};
 
 fn main() {
@@ -51,7 +54,17 @@ fn try_main() -> Result<()> {
 
     debug!("compsvc is starting as a rpc service.");
     let param = ptr::null_mut();
-    let mut service = compsvc::new_binder()?.as_binder();
+    let task_stats = Arc::new(TaskStats::default());
+    let mut service = compsvc::new_binder(task_stats.clone())?.as_binder();
+
+    // The payload server below handles one request at a time, so serve health checks separately
+    // to let them be answered while odrefresh is running.
+    let health_service = compos_health::new_binder(task_stats).as_binder();
+    let health_server =
+        RpcServer::new_vsock(health_service, libc::VMADDR_CID_HOST, COMPOS_HEALTH_VSOCK_PORT)
+            .context("Failed to start health server")?;
+    health_server.start();
+
     let service = service.as_native_mut() as *mut AIBinder;
     // SAFETY: We hold a strong pointer, so the raw pointer remains valid. The bindgen AIBinder
     // is the same type as sys::AIBinder. It is safe for on_ready to be invoked at any time, with
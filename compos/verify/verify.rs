@@ -21,6 +21,7 @@ use android_logger::LogId;
 use anyhow::{anyhow, bail, Context, Result};
 use binder::ProcessState;
 use clap::{Parser, ValueEnum};
+use compos_common::artifacts::{verify_artifacts, SignatureInfo, INFO_FILE, SIGNATURE_FILE};
 use compos_common::compos_client::{ComposClient, VmCpuTopology, VmParameters};
 use compos_common::odrefresh::{
     CURRENT_ARTIFACTS_SUBDIR, ODREFRESH_OUTPUT_ROOT_DIR, PENDING_ARTIFACTS_SUBDIR,
@@ -30,7 +31,7 @@ use compos_common::{
     COMPOS_DATA_ROOT, CURRENT_INSTANCE_DIR, IDSIG_FILE, IDSIG_MANIFEST_APK_FILE,
     IDSIG_MANIFEST_EXT_APK_FILE, INSTANCE_ID_FILE, INSTANCE_IMAGE_FILE, TEST_INSTANCE_DIR,
 };
-use log::error;
+use log::{error, info};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
@@ -48,6 +49,11 @@ struct Args {
     /// Starts the VM in debug mode
     #[clap(long, action)]
     debug: bool,
+
+    /// Also checks that the artifacts directory holds exactly the files listed in compos.info,
+    /// with matching fs-verity digests
+    #[clap(long, action)]
+    check_artifacts: bool,
 }
 
 #[derive(ValueEnum, Clone)]
@@ -104,8 +110,8 @@ fn try_main() -> Result<()> {
     };
     let instance_image = File::open(instance_image).context("Failed to open instance image")?;
 
-    let info = artifacts_dir.join("compos.info");
-    let signature = artifacts_dir.join("compos.info.signature");
+    let info = artifacts_dir.join(INFO_FILE);
+    let signature = artifacts_dir.join(SIGNATURE_FILE);
 
     let info = read_small_file(&info).context("Failed to read compos.info")?;
     let signature = read_small_file(&signature).context("Failed to read compos.info signature")?;
@@ -134,8 +140,13 @@ fn try_main() -> Result<()> {
     let public_key = service.getPublicKey().context("Getting public key");
 
     vm_instance.shutdown(service);
+    let public_key = public_key?;
 
-    if !compos_verify_native::verify(&public_key?, &signature, &info) {
+    if args.check_artifacts {
+        let verified =
+            verify_artifacts(&public_key, &artifacts_dir, &SignatureInfo { info, signature })?;
+        info!("Verified {} artifacts in {:?}", verified.files.len(), artifacts_dir);
+    } else if !compos_verify_native::verify(&public_key, &signature, &info) {
         bail!("Signature verification failed");
     }
 
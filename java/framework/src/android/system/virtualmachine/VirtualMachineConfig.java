@@ -39,6 +39,7 @@ import android.os.Build;
 import android.os.ParcelFileDescriptor;
 import android.os.PersistableBundle;
 import android.sysprop.HypervisorProperties;
+import android.system.virtualizationservice.ConsoleDevice;
 import android.system.virtualizationservice.DiskImage;
 import android.system.virtualizationservice.Partition;
 import android.system.virtualizationservice.VirtualMachineAppConfig;
@@ -720,6 +721,7 @@ public final class VirtualMachineConfig {
         config.cpuTopology = (byte) this.mCpuTopology;
         config.consoleInputDevice = mConsoleInputDevice;
         config.devices = EMPTY_STRING_ARRAY;
+        config.consoleDevices = new ConsoleDevice[0];
         config.networkSupported = this.mNetworkSupported;
         config.platformVersion = "~1.0";
         return config;
@@ -15,6 +15,8 @@
 //! Struct for VM configuration with JSON (de)serialization and AIDL parcelables
 
 use android_system_virtualizationservice::{
+    aidl::android::system::virtualizationservice::ConsoleDevice::ConsoleDevice as AidlConsoleDevice,
+    aidl::android::system::virtualizationservice::ConsoleType::ConsoleType as AidlConsoleType,
     aidl::android::system::virtualizationservice::CpuTopology::CpuTopology,
     aidl::android::system::virtualizationservice::DiskImage::DiskImage as AidlDiskImage,
     aidl::android::system::virtualizationservice::Partition::Partition as AidlPartition,
@@ -27,6 +29,7 @@ use android_system_virtualizationservice::{
 use anyhow::{anyhow, bail, Context, Error, Result};
 use semver::VersionReq;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io::BufReader;
@@ -67,6 +70,9 @@ pub struct VmConfig {
     pub devices: Vec<PathBuf>,
     /// The serial device for VM console input.
     pub console_input_device: Option<String>,
+    /// Console devices of the VM. If empty, the console output goes to both ttyS0 and hvc0.
+    #[serde(default)]
+    pub consoles: Vec<ConsoleDevice>,
 }
 
 impl VmConfig {
@@ -80,10 +86,16 @@ impl VmConfig {
             bail!("Can't have both bootloader and kernel/initrd image.");
         }
         for disk in &self.disks {
-            if disk.image.is_none() == disk.partitions.is_empty() {
-                bail!("Exactly one of image and partitions must be specified. (Was {:?}.)", disk);
+            disk.validate()?;
+        }
+        for console in &self.consoles {
+            if console.earlycon && console.console_type != ConsoleType::Serial {
+                bail!("earlycon is only supported on serial consoles. (Was {:?}.)", console);
             }
         }
+        if self.consoles.iter().filter(|console| console.earlycon).count() > 1 {
+            bail!("At most one console can be used as earlycon.");
+        }
         Ok(())
     }
 
@@ -127,6 +139,7 @@ impl VmConfig {
                 })
                 .collect::<Result<_>>()?,
             consoleInputDevice: self.console_input_device.clone(),
+            consoleDevices: self.consoles.iter().map(ConsoleDevice::to_parcelable).collect(),
             ..Default::default()
         })
     }
@@ -155,6 +168,21 @@ pub struct DiskImage {
 }
 
 impl DiskImage {
+    /// Ensure that the disk image has a valid combination of fields set, or return an error if
+    /// not.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.image.is_none() == self.partitions.is_empty() {
+            bail!("Exactly one of image and partitions must be specified. (Was {:?}.)", self);
+        }
+        let mut labels = HashSet::new();
+        for partition in &self.partitions {
+            if !labels.insert(&partition.label) {
+                bail!("Duplicate partition label {:?}", partition.label);
+            }
+        }
+        Ok(())
+    }
+
     fn to_parcelable(&self) -> Result<AidlDiskImage, Error> {
         let partitions =
             self.partitions.iter().map(Partition::to_parcelable).collect::<Result<_>>()?;
@@ -188,6 +216,46 @@ impl Partition {
     }
 }
 
+/// The hardware emulated for a console device.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsoleType {
+    /// An emulated UART, i.e. /dev/ttyS*.
+    Serial,
+    /// A virtio-console port, i.e. /dev/hvc*.
+    VirtioConsole,
+}
+
+/// A console device of the VM.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConsoleDevice {
+    /// The hardware emulated for the device: either "serial" or "virtio-console".
+    #[serde(rename = "type")]
+    pub console_type: ConsoleType,
+    /// Whether the kernel uses this device as early console. Only supported for serial devices.
+    #[serde(default)]
+    pub earlycon: bool,
+    /// The file to write the output of the device to. If not specified, the output goes to the VM
+    /// console output.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl ConsoleDevice {
+    /// Converts the device to its AIDL representation, without its `output`. As for the console
+    /// and log of the VM, it is up to the caller to create the file at `path`, if any.
+    fn to_parcelable(&self) -> AidlConsoleDevice {
+        AidlConsoleDevice {
+            consoleType: match self.console_type {
+                ConsoleType::Serial => AidlConsoleType::SERIAL,
+                ConsoleType::VirtioConsole => AidlConsoleType::VIRTIO_CONSOLE,
+            },
+            earlycon: self.earlycon,
+            output: None,
+        }
+    }
+}
+
 /// Try to open the given file and wrap it in a [`ParcelFileDescriptor`].
 pub fn open_parcel_file(filename: &Path, writable: bool) -> Result<ParcelFileDescriptor> {
     Ok(ParcelFileDescriptor::new(
@@ -206,3 +274,130 @@ fn maybe_open_parcel_file(
 ) -> Result<Option<ParcelFileDescriptor>> {
     filename.as_deref().map(|filename| open_parcel_file(filename, writable)).transpose()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse(json: &str) -> Result<VmConfig> {
+        let config: VmConfig = serde_json::from_str(json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn consoles_default_to_empty() -> Result<()> {
+        let config = parse(r#"{"kernel": "/kernel", "platform_version": "~1.0"}"#)?;
+        assert!(config.consoles.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_consoles() -> Result<()> {
+        let config = parse(
+            r#"{
+                "kernel": "/kernel",
+                "platform_version": "~1.0",
+                "consoles": [
+                    {"type": "serial", "earlycon": true, "path": "/data/local/tmp/earlycon.log"},
+                    {"type": "virtio-console"}
+                ]
+            }"#,
+        )?;
+        assert_eq!(
+            config.consoles,
+            [
+                ConsoleDevice {
+                    console_type: ConsoleType::Serial,
+                    earlycon: true,
+                    path: Some("/data/local/tmp/earlycon.log".into()),
+                },
+                ConsoleDevice {
+                    console_type: ConsoleType::VirtioConsole,
+                    earlycon: false,
+                    path: None,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_console_type() {
+        let result = parse(
+            r#"{"kernel": "/kernel", "platform_version": "~1.0", "consoles": [{"type": "usb"}]}"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_earlycon_on_virtio_console() {
+        let result = parse(
+            r#"{
+                "kernel": "/kernel",
+                "platform_version": "~1.0",
+                "consoles": [{"type": "virtio-console", "earlycon": true}]
+            }"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_multiple_earlycon() {
+        let result = parse(
+            r#"{
+                "kernel": "/kernel",
+                "platform_version": "~1.0",
+                "consoles": [
+                    {"type": "serial", "earlycon": true},
+                    {"type": "serial", "earlycon": true}
+                ]
+            }"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_duplicate_partition_labels() {
+        let result = parse(
+            r#"{
+                "kernel": "/kernel",
+                "platform_version": "~1.0",
+                "disks": [{
+                    "partitions": [
+                        {"label": "boot", "path": "/boot_a.img"},
+                        {"label": "boot", "path": "/boot_b.img"}
+                    ],
+                    "writable": false
+                }]
+            }"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn console_to_parcelable() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("console.log");
+        let console = ConsoleDevice {
+            console_type: ConsoleType::Serial,
+            earlycon: true,
+            path: Some(path.clone()),
+        };
+
+        let parcelable = console.to_parcelable();
+        assert_eq!(parcelable.consoleType, AidlConsoleType::SERIAL);
+        assert!(parcelable.earlycon);
+        // The output is left to the caller to open.
+        assert!(parcelable.output.is_none());
+        assert!(!path.exists());
+
+        let console =
+            ConsoleDevice { console_type: ConsoleType::VirtioConsole, earlycon: false, path: None };
+        let parcelable = console.to_parcelable();
+        assert_eq!(parcelable.consoleType, AidlConsoleType::VIRTIO_CONSOLE);
+        assert!(parcelable.output.is_none());
+        Ok(())
+    }
+}
@@ -15,9 +15,9 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::{get_calling_pid, get_calling_uid, get_this_pid};
-use crate::atom::{write_vm_booted_stats, write_vm_creation_stats};
+use crate::atom::{get_num_cpus, write_vm_booted_stats, write_vm_creation_stats};
 use crate::composite::make_composite_image;
-use crate::crosvm::{CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, VmContext, VmInstance, VmState};
+use crate::crosvm::{ConsoleDevice, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, VmContext, VmInstance, VmState};
 use crate::debug_config::DebugConfig;
 use crate::dt_overlay::{create_device_tree_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH};
 use crate::payload::{add_microdroid_payload_images, add_microdroid_system_images, add_microdroid_vendor_image};
@@ -30,6 +30,7 @@ use android_system_virtualizationcommon::aidl::android::system::virtualizationco
 };
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     AssignableDevice::AssignableDevice,
+    ConsoleDevice::ConsoleDevice as ConsoleDeviceParcelable,
     CpuTopology::CpuTopology,
     DiskImage::DiskImage,
     InputDevice::InputDevice,
@@ -492,8 +493,23 @@ impl VirtualizationService {
             .collect::<Result<Vec<DiskFile>, _>>()?;
 
         let (cpus, host_cpu_topology) = match config.cpuTopology {
+            CpuTopology::MATCH_HOST | CpuTopology::ONE_CPU if config.cpuCount != 0 => {
+                return Err(anyhow!("cpuCount can only be used with CpuTopology::CUSTOM"))
+                    .with_log()
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+            }
             CpuTopology::MATCH_HOST => (None, true),
             CpuTopology::ONE_CPU => (NonZeroU32::new(1), false),
+            CpuTopology::CUSTOM => {
+                let host_cpu_count = get_num_cpus()
+                    .context("Failed to determine the number of CPUs in the host")
+                    .with_log()
+                    .or_service_specific_exception(-1)?;
+                let cpus = check_cpu_count(config.cpuCount, host_cpu_count)
+                    .with_log()
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+                (Some(cpus), false)
+            }
             val => {
                 return Err(anyhow!("Failed to parse CPU topology value {:?}", val))
                     .with_log()
@@ -609,6 +625,11 @@ impl VirtualizationService {
             tap,
             virtio_snd_backend,
             console_input_device: config.consoleInputDevice.clone(),
+            console_devices: config
+                .consoleDevices
+                .iter()
+                .map(to_console_device_from)
+                .collect::<binder::Result<_>>()?,
             boost_uclamp: config.boostUclamp,
             gpu_config,
         };
@@ -777,6 +798,15 @@ fn round_up(input: u64, granularity: u64) -> u64 {
     (result / granularity) * granularity
 }
 
+/// Checks that a VM can be given `cpu_count` vCPUs: at least one, and no more than the host has.
+fn check_cpu_count(cpu_count: i32, host_cpu_count: usize) -> Result<NonZeroU32> {
+    u32::try_from(cpu_count)
+        .ok()
+        .and_then(NonZeroU32::new)
+        .filter(|count| count.get() as usize <= host_cpu_count)
+        .ok_or_else(|| anyhow!("Invalid cpuCount {cpu_count}, the host has {host_cpu_count} CPUs"))
+}
+
 fn to_input_device_option_from(input_device: &InputDevice) -> Result<InputDeviceOption> {
     Ok(match input_device {
         InputDevice::SingleTouch(single_touch) => InputDeviceOption::SingleTouch {
@@ -800,6 +830,15 @@ fn to_input_device_option_from(input_device: &InputDevice) -> Result<InputDevice
         )?),
     })
 }
+
+fn to_console_device_from(console: &ConsoleDeviceParcelable) -> binder::Result<ConsoleDevice> {
+    Ok(ConsoleDevice {
+        hardware: console.consoleType,
+        earlycon: console.earlycon,
+        output: maybe_clone_file(&console.output)?,
+    })
+}
+
 /// Given the configuration for a disk image, assembles the `DiskFile` to pass to crosvm.
 ///
 /// This may involve assembling a composite disk from a set of partition images.
@@ -971,6 +1010,7 @@ fn load_app_config(
     vm_config.name.clone_from(&config.name);
     vm_config.protectedVm = config.protectedVm;
     vm_config.cpuTopology = config.cpuTopology;
+    vm_config.cpuCount = config.cpuCount;
     vm_config.hugePages = config.hugePages || vm_payload_config.hugepages;
     vm_config.boostUclamp = config.boostUclamp;
 
@@ -1742,6 +1782,15 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_check_cpu_count() {
+        assert_eq!(check_cpu_count(1, 8).unwrap().get(), 1);
+        assert_eq!(check_cpu_count(8, 8).unwrap().get(), 8);
+        assert!(check_cpu_count(9, 8).is_err());
+        assert!(check_cpu_count(0, 8).is_err());
+        assert!(check_cpu_count(-1, 8).is_err());
+    }
+
     #[test]
     fn test_create_or_update_idsig_file_empty_apk() -> Result<()> {
         let apk = tempfile::tempfile().unwrap();
@@ -111,11 +111,12 @@ pub fn write_vm_creation_stats(
             binder_exception_code = e.exception_code() as i32;
         }
     }
-    let (vm_identifier, config_type, cpu_topology, memory_mib, apexes) = match config {
+    let (vm_identifier, config_type, cpu_topology, cpu_count, memory_mib, apexes) = match config {
         VirtualMachineConfig::AppConfig(config) => (
             config.name.clone(),
             vm_creation_requested::ConfigType::VirtualMachineAppConfig,
             config.cpuTopology,
+            config.cpuCount,
             config.memoryMib,
             get_apex_list(config),
         ),
@@ -123,12 +124,14 @@ pub fn write_vm_creation_stats(
             config.name.clone(),
             vm_creation_requested::ConfigType::VirtualMachineRawConfig,
             config.cpuTopology,
+            config.cpuCount,
             config.memoryMib,
             String::new(),
         ),
     };
 
     let num_cpus: i32 = match cpu_topology {
+        CpuTopology::CUSTOM => cpu_count,
         CpuTopology::MATCH_HOST => {
             get_num_cpus().and_then(|v| v.try_into().ok()).unwrap_or_else(|| {
                 warn!("Failed to determine the number of CPUs in the host");
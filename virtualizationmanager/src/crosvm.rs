@@ -44,6 +44,7 @@ use std::time::{Duration, SystemTime};
 use std::thread::{self, JoinHandle};
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    ConsoleType::ConsoleType,
     MemoryTrimLevel::MemoryTrimLevel,
     VirtualMachineAppConfig::DebugLevel::DebugLevel,
     DisplayConfig::DisplayConfig as DisplayConfigParcelable,
@@ -90,6 +91,12 @@ const CONSOLE_HVC0: &str = "hvc0";
 /// Serial (emulated uart)
 const CONSOLE_TTYS0: &str = "ttyS0";
 
+/// Values of `num` that crosvm accepts for console devices of each hardware type, i.e. 1 to 4
+/// minus those used by the fixed devices: ttyS1 for failure reporting, hvc1 for ramdump and hvc2
+/// for logs.
+const SERIAL_CONSOLE_NUMS: [u8; 3] = [1, 3, 4];
+const VIRTIO_CONSOLE_NUMS: [u8; 2] = [1, 4];
+
 lazy_static! {
     /// If the VM doesn't move to the Started state within this amount time, a hang-up error is
     /// triggered.
@@ -133,6 +140,7 @@ pub struct CrosvmConfig {
     pub tap: Option<File>,
     pub virtio_snd_backend: Option<String>,
     pub console_input_device: Option<String>,
+    pub console_devices: Vec<ConsoleDevice>,
     pub boost_uclamp: bool,
     pub gpu_config: Option<GpuConfig>,
 }
@@ -200,6 +208,16 @@ pub struct DiskFile {
     pub writable: bool,
 }
 
+/// A console device of the VM. If `console_devices` is empty, the console output goes to both
+/// ttyS0 and hvc0.
+#[derive(Debug)]
+pub struct ConsoleDevice {
+    pub hardware: ConsoleType,
+    pub earlycon: bool,
+    /// Where the output of the device is written. If none, `console_out_fd` is used.
+    pub output: Option<File>,
+}
+
 /// virtio-input device configuration from `external/crosvm/src/crosvm/config.rs`
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -898,8 +916,9 @@ fn run_vm(
             _ => command.arg("--protected-vm"),
         };
 
-        // 3 virtio-console devices + vsock = 4.
-        let virtio_pci_device_count = 4 + config.disks.len();
+        // virtio-console devices + vsock.
+        let virtio_pci_device_count =
+            virtio_console_count(&config.console_devices) + 1 + config.disks.len();
         // crosvm virtio queue has 256 entries, so 2 MiB per device (2 pages per entry) should be
         // enough.
         let swiotlb_size_mib = 2 * virtio_pci_device_count as u32;
@@ -968,6 +987,7 @@ fn run_vm(
     // 3. virtio-console device: used as the console device where kmsg is redirected to
     // 4. virtio-console device: used as the ramdump output
     // 5. virtio-console device: used as the logcat output
+    // 6. any additional console devices requested in `console_devices`, after the others.
     //
     // When [console|log]_fd is not specified, the devices are attached to sink, which means what's
     // written there is discarded.
@@ -981,33 +1001,53 @@ fn run_vm(
     let failure_serial_path = add_preserved_fd(&mut preserved_fds, &failure_pipe_write);
     let ramdump_arg = format_serial_out_arg(&mut preserved_fds, &config.ramdump);
     let console_input_device = config.console_input_device.as_deref().unwrap_or(CONSOLE_HVC0);
-    match console_input_device {
-        CONSOLE_HVC0 | CONSOLE_TTYS0 => {}
-        _ => bail!("Unsupported serial device {console_input_device}"),
+    let consoles = if config.console_devices.is_empty() {
+        vec![
+            ConsoleArg {
+                hardware: ConsoleType::SERIAL,
+                earlycon: false,
+                output: console_out_arg.clone(),
+            },
+            ConsoleArg {
+                hardware: ConsoleType::VIRTIO_CONSOLE,
+                earlycon: false,
+                output: console_out_arg,
+            },
+        ]
+    } else {
+        config
+            .console_devices
+            .iter()
+            .map(|console| ConsoleArg {
+                hardware: console.hardware,
+                earlycon: console.earlycon,
+                output: if console.output.is_some() {
+                    format_serial_out_arg(&mut preserved_fds, &console.output)
+                } else {
+                    console_out_arg.clone()
+                },
+            })
+            .collect()
     };
+    let (serial_args, virtio_console_args) =
+        format_console_args(consoles, console_input_device, &console_in_arg)?;
 
     // Warning: Adding more serial devices requires you to shift the PCI device ID of the boot
     // disks in bootconfig.x86_64. This is because x86 crosvm puts serial devices and the block
     // devices in the same PCI bus and serial devices comes before the block devices. Arm crosvm
     // doesn't have the issue.
     // /dev/ttyS0
-    command.arg(format!(
-        "--serial={}{},hardware=serial,num=1",
-        &console_out_arg,
-        if console_input_device == CONSOLE_TTYS0 { &console_in_arg } else { "" }
-    ));
+    command.arg(&serial_args[0]);
     // /dev/ttyS1
     command.arg(format!("--serial=type=file,path={},hardware=serial,num=2", &failure_serial_path));
     // /dev/hvc0
-    command.arg(format!(
-        "--serial={}{},hardware=virtio-console,num=1",
-        &console_out_arg,
-        if console_input_device == CONSOLE_HVC0 { &console_in_arg } else { "" }
-    ));
+    command.arg(&virtio_console_args[0]);
     // /dev/hvc1
     command.arg(format!("--serial={},hardware=virtio-console,num=2", &ramdump_arg));
     // /dev/hvc2
     command.arg(format!("--serial={},hardware=virtio-console,num=3", &log_arg));
+    // /dev/ttyS2, /dev/ttyS3 and /dev/hvc3, if requested
+    command.args(&serial_args[1..]).args(&virtio_console_args[1..]);
 
     if let Some(bootloader) = &config.bootloader {
         command.arg("--bios").arg(add_preserved_fd(&mut preserved_fds, bootloader));
@@ -1218,8 +1258,186 @@ fn format_serial_out_arg(preserved_fds: &mut Vec<RawFd>, file: &Option<File>) ->
     }
 }
 
+/// A console device with its output already formatted for a crosvm `--serial` flag.
+#[derive(Debug)]
+struct ConsoleArg {
+    hardware: ConsoleType,
+    earlycon: bool,
+    output: String,
+}
+
+/// Returns the number of virtio-console devices of the VM, including the fixed ones.
+fn virtio_console_count(consoles: &[ConsoleDevice]) -> usize {
+    let requested =
+        consoles.iter().filter(|console| console.hardware == ConsoleType::VIRTIO_CONSOLE).count();
+    // hvc0 is always present, even if no virtio-console device was requested.
+    VIRTIO_CONSOLE_NUMS.len() - 1 + max(requested, 1)
+}
+
+/// Returns the crosvm `--serial` flags for the given console devices, as a list for the uart
+/// devices and one for the virtio-console devices.
+///
+/// Devices of each type are numbered in order, skipping the numbers used by the fixed devices. The
+/// first device of each type (ttyS0 and hvc0) is always present, attached to a sink if none was
+/// requested, and receives the console input if it is the `console_input_device`.
+fn format_console_args(
+    mut consoles: Vec<ConsoleArg>,
+    console_input_device: &str,
+    console_in_arg: &str,
+) -> Result<(Vec<String>, Vec<String>)> {
+    match console_input_device {
+        CONSOLE_HVC0 | CONSOLE_TTYS0 => {}
+        _ => bail!("Unsupported serial device {console_input_device}"),
+    };
+    if consoles.iter().filter(|console| console.earlycon).count() > 1 {
+        bail!("At most one console device can be used as earlycon");
+    }
+
+    for hardware in [ConsoleType::SERIAL, ConsoleType::VIRTIO_CONSOLE] {
+        if !consoles.iter().any(|console| console.hardware == hardware) {
+            consoles.push(ConsoleArg { hardware, earlycon: false, output: "type=sink".to_owned() });
+        }
+    }
+
+    let mut serial = vec![];
+    let mut virtio_console = vec![];
+    for console in consoles {
+        let (args, nums, hardware, first_device) = match console.hardware {
+            ConsoleType::SERIAL => (&mut serial, &SERIAL_CONSOLE_NUMS[..], "serial", CONSOLE_TTYS0),
+            ConsoleType::VIRTIO_CONSOLE => {
+                if console.earlycon {
+                    bail!("earlycon is not supported on virtio-console devices");
+                }
+                (&mut virtio_console, &VIRTIO_CONSOLE_NUMS[..], "virtio-console", CONSOLE_HVC0)
+            }
+            hardware => bail!("Unsupported console device type {hardware:?}"),
+        };
+        let Some(num) = nums.get(args.len()) else {
+            bail!("Too many {hardware} console devices, at most {} are supported", nums.len());
+        };
+        let input = if args.is_empty() && console_input_device == first_device {
+            console_in_arg
+        } else {
+            ""
+        };
+        let earlycon = if console.earlycon { ",earlycon=true" } else { "" };
+        args.push(format!(
+            "--serial={}{input},hardware={hardware},num={num}{earlycon}",
+            console.output
+        ));
+    }
+    Ok((serial, virtio_console))
+}
+
 /// Creates a new pipe with the `O_CLOEXEC` flag set, and returns the read side and write side.
 fn create_pipe() -> Result<(File, File), Error> {
     let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
     Ok((read_fd.into(), write_fd.into()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn console(hardware: ConsoleType, earlycon: bool, output: &str) -> ConsoleArg {
+        ConsoleArg { hardware, earlycon, output: output.to_owned() }
+    }
+
+    #[test]
+    fn test_default_console_args() -> Result<()> {
+        let consoles = vec![
+            console(ConsoleType::SERIAL, false, "type=file,path=/proc/self/fd/3"),
+            console(ConsoleType::VIRTIO_CONSOLE, false, "type=file,path=/proc/self/fd/3"),
+        ];
+        let (serial, virtio_console) =
+            format_console_args(consoles, CONSOLE_HVC0, ",input=/proc/self/fd/4")?;
+
+        assert_eq!(serial, ["--serial=type=file,path=/proc/self/fd/3,hardware=serial,num=1"]);
+        assert_eq!(
+            virtio_console,
+            ["--serial=type=file,path=/proc/self/fd/3,input=/proc/self/fd/4,hardware=virtio-console,num=1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_args_skip_fixed_devices() -> Result<()> {
+        let consoles = vec![
+            console(ConsoleType::SERIAL, true, "type=file,path=/proc/self/fd/3"),
+            console(ConsoleType::VIRTIO_CONSOLE, false, "type=sink"),
+            console(ConsoleType::SERIAL, false, "type=file,path=/proc/self/fd/5"),
+            console(ConsoleType::VIRTIO_CONSOLE, false, "type=file,path=/proc/self/fd/6"),
+            console(ConsoleType::SERIAL, false, "type=sink"),
+        ];
+        let (serial, virtio_console) =
+            format_console_args(consoles, CONSOLE_TTYS0, ",input=/proc/self/fd/4")?;
+
+        assert_eq!(
+            serial,
+            [
+                "--serial=type=file,path=/proc/self/fd/3,input=/proc/self/fd/4,hardware=serial,num=1,earlycon=true",
+                "--serial=type=file,path=/proc/self/fd/5,hardware=serial,num=3",
+                "--serial=type=sink,hardware=serial,num=4",
+            ]
+        );
+        assert_eq!(
+            virtio_console,
+            [
+                "--serial=type=sink,hardware=virtio-console,num=1",
+                "--serial=type=file,path=/proc/self/fd/6,hardware=virtio-console,num=4",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_args_without_serial_console() -> Result<()> {
+        let consoles = vec![console(ConsoleType::VIRTIO_CONSOLE, false, "type=sink")];
+        let (serial, virtio_console) =
+            format_console_args(consoles, CONSOLE_TTYS0, ",input=/proc/self/fd/4")?;
+
+        assert_eq!(serial, ["--serial=type=sink,input=/proc/self/fd/4,hardware=serial,num=1"]);
+        assert_eq!(virtio_console, ["--serial=type=sink,hardware=virtio-console,num=1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_args_reject_earlycon_on_virtio_console() {
+        let consoles = vec![console(ConsoleType::VIRTIO_CONSOLE, true, "type=sink")];
+        assert!(format_console_args(consoles, CONSOLE_HVC0, "").is_err());
+    }
+
+    #[test]
+    fn test_console_args_reject_multiple_earlycon() {
+        let consoles = vec![
+            console(ConsoleType::SERIAL, true, "type=sink"),
+            console(ConsoleType::SERIAL, true, "type=sink"),
+        ];
+        assert!(format_console_args(consoles, CONSOLE_HVC0, "").is_err());
+    }
+
+    #[test]
+    fn test_console_args_reject_too_many_devices() {
+        let consoles = (0..3).map(|_| console(ConsoleType::VIRTIO_CONSOLE, false, "type=sink"));
+        assert!(format_console_args(consoles.collect(), CONSOLE_HVC0, "").is_err());
+    }
+
+    #[test]
+    fn test_console_args_reject_unknown_input_device() {
+        assert!(format_console_args(vec![], "ttyS1", "").is_err());
+    }
+
+    #[test]
+    fn test_virtio_console_count() {
+        let virtio_console = || ConsoleDevice {
+            hardware: ConsoleType::VIRTIO_CONSOLE,
+            earlycon: false,
+            output: None,
+        };
+        let serial = ConsoleDevice { hardware: ConsoleType::SERIAL, earlycon: false, output: None };
+
+        assert_eq!(virtio_console_count(&[]), 3);
+        assert_eq!(virtio_console_count(&[serial]), 3);
+        assert_eq!(virtio_console_count(&[virtio_console(), virtio_console()]), 4);
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for running a VM in a background process, while the foreground process waits for it to
+//! start.
+
+use anyhow::{anyhow, bail, Context, Error};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use vmclient::DeathReason;
+
+/// A milestone in the startup of the VM, reported by the background process.
+#[derive(Debug, PartialEq, Eq)]
+enum StartupEvent {
+    /// The VM has been started.
+    Started,
+    /// The payload has reported that it is ready.
+    Ready,
+    /// The VM has died, for the given reason.
+    Died(String),
+    /// The background process failed to run the VM, with the given error chain.
+    Failed(String),
+}
+
+impl StartupEvent {
+    fn to_line(&self) -> String {
+        match self {
+            Self::Started => "started\n".to_owned(),
+            Self::Ready => "ready\n".to_owned(),
+            Self::Died(reason) => format!("died {}\n", reason),
+            Self::Failed(error) => format!("failed {}\n", error),
+        }
+    }
+
+    fn from_line(line: &str) -> Result<Self, Error> {
+        match line.trim_end() {
+            "started" => Ok(Self::Started),
+            "ready" => Ok(Self::Ready),
+            line => {
+                if let Some(reason) = line.strip_prefix("died ") {
+                    Ok(Self::Died(reason.to_owned()))
+                } else if let Some(error) = line.strip_prefix("failed ") {
+                    Ok(Self::Failed(error.to_owned()))
+                } else {
+                    bail!("Unexpected startup event {:?}", line)
+                }
+            }
+        }
+    }
+}
+
+/// Reports the startup of the VM from the background process to the foreground one.
+pub struct StartupNotifier(Mutex<File>);
+
+impl StartupNotifier {
+    fn new(file: File) -> Self {
+        Self(Mutex::new(file))
+    }
+
+    /// Reports that the VM has been started.
+    pub fn started(&self) {
+        self.notify(StartupEvent::Started)
+    }
+
+    /// Reports that the payload is ready.
+    pub fn ready(&self) {
+        self.notify(StartupEvent::Ready)
+    }
+
+    /// Reports that the VM has died.
+    pub fn died(&self, death_reason: DeathReason) {
+        self.notify(StartupEvent::Died(format!("{:?}", death_reason)))
+    }
+
+    /// Reports that the VM couldn't be run because of `error`.
+    pub fn failed(&self, error: &Error) {
+        // Events are one per line, so the error chain is flattened to a single line.
+        self.notify(StartupEvent::Failed(format!("{:#}", error).replace('\n', " ")))
+    }
+
+    fn notify(&self, event: StartupEvent) {
+        // The foreground process stops listening once it has what it waited for, so failures here
+        // are expected and harmless.
+        let _ignored = self.0.lock().unwrap().write_all(event.to_line().as_bytes());
+    }
+}
+
+/// Forks the process so that the VM is run in the background.
+///
+/// This returns in the background process, which should go on to create and run the VM, reporting
+/// its startup, or why it failed, through the returned notifier. The foreground process instead
+/// waits until the VM has started (or is ready, if `wait_until_ready` is set) and then exits,
+/// successfully unless the VM failed, died or didn't make it within `timeout`.
+///
+/// The standard streams of the background process are redirected to /dev/null, so that the VM
+/// doesn't keep the terminal (or the output pipe of a non-interactive shell) open. Its console and
+/// log are therefore discarded unless they are written to files.
+///
+/// This must be called before any other thread is started. As the background process owns the VM
+/// from its creation, no callback can be missed while handing the VM over.
+pub fn daemonize(wait_until_ready: bool, timeout: Duration) -> Result<StartupNotifier, Error> {
+    let (read_end, write_end) = pipe()?;
+
+    // SAFETY: We are still single-threaded, so the child can safely carry on running Rust code.
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to fork");
+    }
+
+    if pid == 0 {
+        drop(read_end);
+        // Detach from the controlling terminal so that the VM survives the shell session.
+        // SAFETY: setsid has no memory safety implications.
+        if unsafe { libc::setsid() } < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create a new session");
+        }
+        detach_stdio()?;
+        return Ok(StartupNotifier::new(write_end));
+    }
+
+    drop(write_end);
+    match wait_for_startup(read_end, wait_until_ready, timeout) {
+        Ok(()) => {
+            println!("VM is running in the background (pid {}).", pid);
+            process::exit(0)
+        }
+        Err(e) => {
+            eprintln!("{:?}", e);
+            // SAFETY: kill has no memory safety implications. The VM is killed with the process.
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            process::exit(1)
+        }
+    }
+}
+
+/// Points the standard streams of the process to /dev/null.
+fn detach_stdio() -> Result<(), Error> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: dup2 only replaces a standard stream with a file descriptor that we own.
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to redirect standard streams");
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the events read from `events` to report that the VM has started (or is ready, if
+/// `wait_until_ready` is set), failing if it fails, dies or `timeout` elapses first.
+pub(crate) fn wait_for_startup(
+    events: File,
+    wait_until_ready: bool,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(events).lines() {
+            let event = line.map_err(Error::from).and_then(|line| StartupEvent::from_line(&line));
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+        // Dropping the sender reports that the background process is gone.
+    });
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let event = match receiver.recv_timeout(remaining) {
+            Ok(event) => event?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                bail!("Timed out after {:?} waiting for the VM", timeout)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                bail!("The background process exited before the VM was started")
+            }
+        };
+        match event {
+            StartupEvent::Started if !wait_until_ready => return Ok(()),
+            StartupEvent::Started => {}
+            StartupEvent::Ready => return Ok(()),
+            StartupEvent::Died(reason) => {
+                return Err(anyhow!("VM died before it was ready: {}", reason))
+            }
+            StartupEvent::Failed(error) => return Err(anyhow!("Failed to run the VM: {}", error)),
+        }
+    }
+}
+
+/// Returns a notifier and the file from which the events it reports can be read, as `daemonize`
+/// would set them up.
+#[cfg(test)]
+pub(crate) fn startup_channel() -> Result<(StartupNotifier, File), Error> {
+    let (read_end, write_end) = pipe()?;
+    Ok((StartupNotifier::new(write_end), read_end))
+}
+
+fn pipe() -> Result<(File, File), Error> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 only writes two file descriptors to the array we pass it.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create pipe");
+    }
+    // SAFETY: pipe2 succeeded, so we own both file descriptors.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn event_round_trip() -> Result<(), Error> {
+        for event in [
+            StartupEvent::Started,
+            StartupEvent::Ready,
+            StartupEvent::Died("Crash".into()),
+            StartupEvent::Failed("Failed to create VM: No such file".into()),
+        ] {
+            assert_eq!(StartupEvent::from_line(&event.to_line())?, event);
+        }
+        assert!(StartupEvent::from_line("bogus\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ready_then_exit() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        notifier.started();
+        notifier.ready();
+        wait_for_startup(read_end, true, TIMEOUT)
+    }
+
+    #[test]
+    fn started_is_enough_without_wait_until_ready() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        notifier.started();
+        wait_for_startup(read_end, false, TIMEOUT)
+    }
+
+    #[test]
+    fn die_before_ready() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        notifier.started();
+        notifier.died(DeathReason::Crash);
+
+        let error = wait_for_startup(read_end, true, TIMEOUT).unwrap_err();
+        assert_eq!(error.to_string(), "VM died before it was ready: Crash");
+        Ok(())
+    }
+
+    #[test]
+    fn failure_is_reported_with_its_cause() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        let error = anyhow!("Permission denied").context("Failed to open log file\n\"vm.log\"");
+        notifier.failed(&error);
+
+        let error = wait_for_startup(read_end, false, TIMEOUT).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to run the VM: Failed to open log file \"vm.log\": Permission denied"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn background_process_exits_before_ready() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        notifier.started();
+        drop(notifier);
+
+        assert!(wait_for_startup(read_end, true, TIMEOUT).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn times_out_if_never_ready() -> Result<(), Error> {
+        let (read_end, write_end) = pipe()?;
+        let notifier = StartupNotifier::new(write_end);
+        notifier.started();
+
+        let error = wait_for_startup(read_end, true, Duration::from_millis(100)).unwrap_err();
+        assert!(error.to_string().starts_with("Timed out"), "{error}");
+        drop(notifier);
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to create disk images for VMs
+
+use crate::verify_config::check_disk;
+use anyhow::{bail, ensure, Context, Error};
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::process::Command;
+use vmconfig::{DiskImage, Partition};
+
+const MKE2FS_BIN: &str = "/system/bin/mke2fs";
+
+/// A filesystem that a disk image can be formatted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    Ext4,
+}
+
+/// Create a disk image and print the entry to add to the disks of a VM config to use it.
+///
+/// If `partitions` is empty, this creates a sparse image of `size_mib` MiB at `path`, optionally
+/// formatted with `filesystem`. Otherwise, this writes a disk config made of the given partitions
+/// to `path`, in the JSON format of the disks of a VM config. No image is created in that case: the
+/// partitions are only assembled into a composite disk when the VM is started.
+pub fn command_disk_create(
+    path: &Path,
+    size_mib: Option<u64>,
+    filesystem: Option<Filesystem>,
+    partitions: Vec<Partition>,
+) -> Result<(), Error> {
+    let disk = if partitions.is_empty() {
+        let Some(size_mib) = size_mib else {
+            bail!("The size of the disk image must be specified");
+        };
+        create_empty_image(path, size_mib)?;
+        if let Some(filesystem) = filesystem {
+            if let Err(e) = format_image(path, filesystem) {
+                // Don't leave behind an unformatted image that could be mistaken for a usable one.
+                let _ignored = fs::remove_file(path);
+                return Err(e);
+            }
+        }
+        DiskImage { image: Some(fs::canonicalize(path)?), partitions: vec![], writable: true }
+    } else {
+        create_disk_config(path, partitions)?
+    };
+    // Make sure that the disk will be accepted when referenced from a VM config.
+    check_disk(&disk).with_context(|| format!("Created invalid disk {:?}", path))?;
+
+    println!("Created {:?}. To use it, add this to the disks of the VM config:", path);
+    println!("{}", serde_json::to_string_pretty(&disk)?);
+    Ok(())
+}
+
+fn create_empty_image(path: &Path, size_mib: u64) -> Result<(), Error> {
+    ensure!(size_mib > 0, "The size of the disk image must be positive");
+    let size = size_mib.checked_mul(1024 * 1024).context("Disk image size is too large")?;
+    let image = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    // Extending the file doesn't allocate blocks, so the image is sparse.
+    image.set_len(size).with_context(|| format!("Failed to resize {:?}", path))?;
+    Ok(())
+}
+
+fn format_image(path: &Path, filesystem: Filesystem) -> Result<(), Error> {
+    let fs_type = match filesystem {
+        Filesystem::Ext4 => "ext4",
+    };
+    let status = Command::new(MKE2FS_BIN)
+        .args(["-F", "-q", "-t", fs_type])
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run {}", MKE2FS_BIN))?;
+    ensure!(status.success(), "Formatting {:?} failed with {:?}", path, status);
+    Ok(())
+}
+
+/// Writes a disk config made of `partitions` to `path`, in the format of the disks of a VM config.
+fn create_disk_config(path: &Path, partitions: Vec<Partition>) -> Result<DiskImage, Error> {
+    let partitions = partitions
+        .into_iter()
+        .map(|partition| {
+            // The disk config may be used from another directory.
+            let image = fs::canonicalize(&partition.path)
+                .with_context(|| format!("Failed to find partition image {:?}", partition.path))?;
+            Ok(Partition { path: image, ..partition })
+        })
+        .collect::<Result<_, Error>>()?;
+    let disk = DiskImage { image: None, partitions, writable: false };
+    check_disk(&disk)?;
+
+    let file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    serde_json::to_writer_pretty(file, &disk)?;
+
+    // Read the disk config back, as a VM config would.
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to parse {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_config::verify_config;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn partition(label: &str, path: &Path) -> Partition {
+        Partition { label: label.to_owned(), path: path.to_owned(), writable: false }
+    }
+
+    fn write_file(dir: &TempDir, name: &str) -> Result<PathBuf, Error> {
+        let path = dir.path().join(name);
+        fs::write(&path, [0u8; 4096])?;
+        Ok(path)
+    }
+
+    #[test]
+    fn create_sparse_image() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("disk.img");
+        command_disk_create(&path, Some(16), None, vec![])?;
+        assert_eq!(fs::metadata(&path)?.len(), 16 * 1024 * 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn existing_image_is_not_overwritten() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = write_file(&dir, "disk.img")?;
+        assert!(command_disk_create(&path, Some(16), None, vec![]).is_err());
+        assert_eq!(fs::metadata(&path)?.len(), 4096);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_image_needs_size() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("disk.img");
+        assert!(command_disk_create(&path, None, None, vec![]).is_err());
+        assert!(command_disk_create(&path, Some(0), None, vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn two_partition_disk_config_passes_verify_config() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let kernel = write_file(&dir, "kernel")?;
+        let boot = write_file(&dir, "boot.img")?;
+        let system = write_file(&dir, "system.img")?;
+        let disk_config = dir.path().join("disk.json");
+        command_disk_create(
+            &disk_config,
+            None,
+            None,
+            vec![partition("boot", &boot), partition("system", &system)],
+        )?;
+
+        let disk: serde_json::Value = serde_json::from_reader(File::open(&disk_config)?)?;
+        let config = serde_json::json!({
+            "kernel": kernel,
+            "disks": [disk],
+            "platform_version": "~1.0",
+        });
+        let config_path = dir.path().join("vm_config.json");
+        fs::write(&config_path, config.to_string())?;
+
+        let config = verify_config(&config_path)?;
+        assert_eq!(config.disks.len(), 1);
+        let labels: Vec<_> = config.disks[0].partitions.iter().map(|p| p.label.as_str()).collect();
+        assert_eq!(labels, ["boot", "system"]);
+        assert_eq!(config.disks[0].partitions[1].path, fs::canonicalize(&system)?);
+        Ok(())
+    }
+
+    #[test]
+    fn disk_config_with_duplicate_labels_is_rejected() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let boot = write_file(&dir, "boot.img")?;
+        let disk_config = dir.path().join("disk.json");
+        let partitions = vec![partition("boot", &boot), partition("boot", &boot)];
+        assert!(command_disk_create(&disk_config, None, None, partitions).is_err());
+        assert!(!disk_config.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn disk_config_with_missing_partition_is_rejected() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let disk_config = dir.path().join("disk.json");
+        let partitions = vec![partition("boot", &dir.path().join("missing.img"))];
+        assert!(command_disk_create(&disk_config, None, None, partitions).is_err());
+        Ok(())
+    }
+}
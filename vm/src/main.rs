@@ -16,7 +16,10 @@
 
 mod create_idsig;
 mod create_partition;
+mod daemon;
+mod disk;
 mod run;
+mod verify_config;
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     CpuTopology::CpuTopology, IVirtualizationService::IVirtualizationService,
@@ -26,13 +29,19 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 use anyhow::anyhow;
 use anyhow::{Context, Error};
 use binder::{ProcessState, Strong};
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
 use create_idsig::command_create_idsig;
 use create_partition::command_create_partition;
+use daemon::daemonize;
+use disk::{command_disk_create, Filesystem};
 use run::{command_run, command_run_app, command_run_microdroid};
 use serde::Serialize;
 use std::num::NonZeroU16;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use verify_config::command_verify_config;
+use vmconfig::{ConsoleDevice, ConsoleType, Partition};
 
 #[derive(Args, Default)]
 /// Collection of flags that are at VM level and therefore applicable to all subcommands
@@ -213,6 +222,20 @@ pub struct RunAppConfig {
     /// Paths to extra idsig files.
     #[arg(long = "extra-idsig")]
     extra_idsigs: Vec<PathBuf>,
+
+    /// Run the VM in the background, returning once it has started. The console and log of the VM
+    /// are discarded unless --console and --log are given.
+    #[arg(long)]
+    daemonize: bool,
+
+    /// With --daemonize, return only once the payload is ready, failing if the VM dies first.
+    #[arg(long, requires = "daemonize")]
+    wait_until_ready: bool,
+
+    /// With --daemonize, how long to wait in seconds for the VM to start, or to be ready if
+    /// --wait-until-ready is given.
+    #[arg(long, requires = "daemonize", default_value = "120")]
+    boot_timeout: u64,
 }
 
 impl RunAppConfig {
@@ -278,6 +301,20 @@ pub struct RunCustomVmConfig {
 
     /// Path to VM config JSON
     config: PathBuf,
+
+    /// Path to a kernel image to boot instead of the one in the VM config JSON
+    #[arg(long)]
+    kernel: Option<PathBuf>,
+
+    /// Path to an initrd to use instead of the one in the VM config JSON
+    #[arg(long)]
+    initrd: Option<PathBuf>,
+
+    /// Console device of the VM, as TYPE[,earlycon][,path=PATH] where TYPE is either "serial" or
+    /// "virtio-console". Can be repeated, in which case the devices are numbered in order. If
+    /// specified, replaces the consoles in the VM config JSON.
+    #[arg(long = "console-device", value_parser = parse_console_device)]
+    console_devices: Vec<ConsoleDevice>,
 }
 
 #[derive(Parser)]
@@ -316,6 +353,16 @@ enum Opt {
                value_parser = parse_partition_type)]
         partition_type: PartitionType,
     },
+    /// Create disk images for VMs
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommand,
+    },
+    /// Check that a VM config is valid and that the files it references exist
+    VerifyConfig {
+        /// Path to VM config JSON
+        config: PathBuf,
+    },
     /// Creates or update the idsig file by digesting the input APK file.
     CreateIdsig {
         /// Path to VM Payload APK
@@ -326,6 +373,27 @@ enum Opt {
     },
 }
 
+#[derive(Subcommand)]
+enum DiskCommand {
+    /// Create an empty disk image, or a disk config made of partitions
+    Create {
+        /// Path at which to create the image file, or the disk config
+        path: PathBuf,
+
+        /// The size of the image, in MiB
+        #[arg(long, required_unless_present = "partitions", conflicts_with = "partitions")]
+        size: Option<u64>,
+
+        /// Filesystem to format the image with
+        #[arg(long, value_parser = parse_filesystem, conflicts_with = "partitions")]
+        filesystem: Option<Filesystem>,
+
+        /// Partition of the disk config, as LABEL=IMAGE. Can be repeated.
+        #[arg(long = "partition", value_parser = parse_partition)]
+        partitions: Vec<Partition>,
+    },
+}
+
 fn parse_debug_level(s: &str) -> Result<DebugLevel, String> {
     match s {
         "none" => Ok(DebugLevel::NONE),
@@ -350,6 +418,40 @@ fn parse_cpu_topology(s: &str) -> Result<CpuTopology, String> {
     }
 }
 
+fn parse_filesystem(s: &str) -> Result<Filesystem, String> {
+    match s {
+        "ext4" => Ok(Filesystem::Ext4),
+        _ => Err(format!("Invalid filesystem {}", s)),
+    }
+}
+
+fn parse_partition(s: &str) -> Result<Partition, String> {
+    match s.split_once('=') {
+        Some((label, path)) if !label.is_empty() && !path.is_empty() => {
+            Ok(Partition { label: label.to_owned(), path: path.into(), writable: false })
+        }
+        _ => Err(format!("Invalid partition {}, expected LABEL=IMAGE", s)),
+    }
+}
+
+fn parse_console_device(s: &str) -> Result<ConsoleDevice, String> {
+    let mut options = s.split(',');
+    let console_type = match options.next() {
+        Some("serial") => ConsoleType::Serial,
+        Some("virtio-console") => ConsoleType::VirtioConsole,
+        _ => return Err(format!("Invalid console device type in {}", s)),
+    };
+    let mut console = ConsoleDevice { console_type, earlycon: false, path: None };
+    for option in options {
+        match option.split_once('=') {
+            None if option == "earlycon" => console.earlycon = true,
+            Some(("path", path)) if !path.is_empty() => console.path = Some(path.into()),
+            _ => return Err(format!("Invalid console device option {}", option)),
+        }
+    }
+    Ok(console)
+}
+
 fn get_service() -> Result<Strong<dyn IVirtualizationService>, Error> {
     let virtmgr =
         vmclient::VirtualizationService::new().context("Failed to spawn VirtualizationService")?;
@@ -367,6 +469,15 @@ fn main() -> Result<(), Error> {
     env_logger::init();
     let opt = Opt::parse();
 
+    // Fork before starting any thread, so that the background process owns the VM from the start.
+    let notifier = match &opt {
+        Opt::RunApp { config } if config.daemonize => Some(Arc::new(daemonize(
+            config.wait_until_ready,
+            Duration::from_secs(config.boot_timeout),
+        )?)),
+        _ => None,
+    };
+
     // We need to start the thread pool for Binder to work properly, especially link_to_death.
     ProcessState::start_thread_pool();
 
@@ -375,7 +486,14 @@ fn main() -> Result<(), Error> {
             command_check_feature_enabled(&feature);
             Ok(())
         }
-        Opt::RunApp { config } => command_run_app(config),
+        Opt::RunApp { config } => {
+            let result = command_run_app(config, notifier.clone());
+            // Tell the foreground process why the VM couldn't be run, as it can't see our output.
+            if let (Err(e), Some(notifier)) = (&result, &notifier) {
+                notifier.failed(e);
+            }
+            result
+        }
         Opt::RunMicrodroid { config } => command_run_microdroid(config),
         Opt::Run { config } => command_run(config),
         Opt::List => command_list(get_service()?.as_ref()),
@@ -383,6 +501,10 @@ fn main() -> Result<(), Error> {
         Opt::CreatePartition { path, size, partition_type } => {
             command_create_partition(get_service()?.as_ref(), &path, size, partition_type)
         }
+        Opt::Disk { command: DiskCommand::Create { path, size, filesystem, partitions } } => {
+            command_disk_create(&path, size, filesystem, partitions)
+        }
+        Opt::VerifyConfig { config } => command_verify_config(&config),
         Opt::CreateIdsig { apk, path } => {
             command_create_idsig(get_service()?.as_ref(), &apk, &path)
         }
@@ -460,4 +582,67 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         Opt::command().debug_assert();
     }
+
+    #[test]
+    fn parse_console_devices() {
+        assert_eq!(
+            parse_console_device("serial,earlycon,path=/data/local/tmp/earlycon.log"),
+            Ok(ConsoleDevice {
+                console_type: ConsoleType::Serial,
+                earlycon: true,
+                path: Some("/data/local/tmp/earlycon.log".into()),
+            })
+        );
+        assert_eq!(
+            parse_console_device("virtio-console"),
+            Ok(ConsoleDevice {
+                console_type: ConsoleType::VirtioConsole,
+                earlycon: false,
+                path: None
+            })
+        );
+        assert!(parse_console_device("usb").is_err());
+        assert!(parse_console_device("serial,bogus").is_err());
+        assert!(parse_console_device("serial,path=").is_err());
+    }
+
+    #[test]
+    fn parse_partitions() {
+        assert_eq!(
+            parse_partition("boot=/data/local/tmp/boot.img"),
+            Ok(Partition {
+                label: "boot".to_owned(),
+                path: "/data/local/tmp/boot.img".into(),
+                writable: false,
+            })
+        );
+        assert!(parse_partition("boot").is_err());
+        assert!(parse_partition("=/data/local/tmp/boot.img").is_err());
+        assert!(parse_partition("boot=").is_err());
+    }
+
+    #[test]
+    fn disk_create_size_conflicts_with_partitions() {
+        let args = ["vm", "disk", "create", "disk.json", "--size", "16", "--partition", "a=a.img"];
+        assert!(Opt::try_parse_from(args).is_err());
+        assert!(Opt::try_parse_from(["vm", "disk", "create", "disk.img"]).is_err());
+        assert!(Opt::try_parse_from(["vm", "disk", "create", "disk.img", "--size", "16"]).is_ok());
+    }
+
+    #[test]
+    fn console_devices_are_repeatable() {
+        let Opt::Run { config } = Opt::parse_from([
+            "vm",
+            "run",
+            "--console-device",
+            "serial,earlycon",
+            "--console-device",
+            "virtio-console,path=/data/local/tmp/hvc.log",
+            "vm_config.json",
+        ]) else {
+            panic!("Expected the run subcommand");
+        };
+        let types: Vec<_> = config.console_devices.iter().map(|c| c.console_type).collect();
+        assert_eq!(types, [ConsoleType::Serial, ConsoleType::VirtioConsole]);
+    }
 }
@@ -15,6 +15,7 @@
 //! Command to run a VM.
 
 use crate::create_partition::command_create_partition;
+use crate::daemon::StartupNotifier;
 use crate::{get_service, RunAppConfig, RunCustomVmConfig, RunMicrodroidConfig};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     IVirtualizationService::IVirtualizationService,
@@ -38,12 +39,19 @@ use std::io;
 use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
-use vmclient::{ErrorCode, VmInstance};
-use vmconfig::{get_debug_level, open_parcel_file, VmConfig};
+use std::sync::Arc;
+use vmclient::{DeathReason, ErrorCode, VmInstance};
+use vmconfig::{get_debug_level, open_parcel_file, ConsoleDevice, VmConfig};
 use zip::ZipArchive;
 
 /// Run a VM from the given APK, idsig, and config.
-pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
+///
+/// If `notifier` is set, this is running in the background and reports the startup of the VM
+/// through it.
+pub fn command_run_app(
+    config: RunAppConfig,
+    notifier: Option<Arc<StartupNotifier>>,
+) -> Result<(), Error> {
     let service = get_service()?;
     let apk = File::open(&config.apk).context("Failed to open APK file")?;
 
@@ -188,6 +196,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        notifier,
     )
 }
 
@@ -240,14 +249,24 @@ pub fn command_run_microdroid(config: RunMicrodroidConfig) -> Result<(), Error>
         println!("instance_id file path: {}", app_config.instance_id()?.display());
     }
 
-    command_run_app(app_config)
+    command_run_app(app_config, None)
 }
 
 /// Run a VM from the given configuration file.
 pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
     let config_file = File::open(&config.config).context("Failed to open config file")?;
-    let mut vm_config =
-        VmConfig::load(&config_file).context("Failed to parse config file")?.to_parcelable()?;
+    let mut vm_config = VmConfig::load(&config_file).context("Failed to parse config file")?;
+    override_kernel_and_initrd(&mut vm_config, config.kernel, config.initrd)?;
+    if !config.console_devices.is_empty() {
+        vm_config.consoles = config.console_devices;
+        vm_config.validate()?;
+    }
+    let summary = describe_custom_vm(&config.config, &vm_config);
+    let console_outputs = open_console_outputs(&vm_config.consoles)?;
+    let mut vm_config = vm_config.to_parcelable()?;
+    for (console_device, output) in vm_config.consoleDevices.iter_mut().zip(console_outputs) {
+        console_device.output = output;
+    }
     if let Some(mem) = config.common.mem {
         vm_config.memoryMib = mem as i32;
     }
@@ -265,13 +284,73 @@ pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
     run(
         get_service()?.as_ref(),
         &VirtualMachineConfig::RawConfig(vm_config),
-        &format!("{:?}", &config.config),
+        &summary,
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        None,
     )
 }
 
+/// Replaces the kernel and/or initrd of the VM config with the given files, if any.
+fn override_kernel_and_initrd(
+    vm_config: &mut VmConfig,
+    kernel: Option<PathBuf>,
+    initrd: Option<PathBuf>,
+) -> Result<(), Error> {
+    if kernel.is_none() && initrd.is_none() {
+        return Ok(());
+    }
+    if vm_config.bootloader.is_some() {
+        bail!("--kernel and --initrd can't be used with a VM config that specifies a bootloader");
+    }
+    for path in kernel.iter().chain(initrd.iter()) {
+        if !path.is_file() {
+            bail!("{:?} does not exist or is not a file", path);
+        }
+    }
+    if kernel.is_some() {
+        vm_config.kernel = kernel;
+    }
+    if initrd.is_some() {
+        vm_config.initrd = initrd;
+    }
+    vm_config.validate()
+}
+
+/// Creates the files that the console devices which have a path write to.
+fn open_console_outputs(
+    consoles: &[ConsoleDevice],
+) -> Result<Vec<Option<ParcelFileDescriptor>>, Error> {
+    consoles
+        .iter()
+        .map(|console| {
+            console
+                .path
+                .as_ref()
+                .map(|path| {
+                    let file = File::create(path).with_context(|| {
+                        format!("Failed to open console output file {:?}", path)
+                    })?;
+                    Ok(ParcelFileDescriptor::new(file))
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+/// Describes the VM config file and the kernel and initrd that are actually booted.
+fn describe_custom_vm(config_path: &Path, vm_config: &VmConfig) -> String {
+    let mut summary = format!("{:?}", config_path);
+    if let Some(kernel) = &vm_config.kernel {
+        summary.push_str(&format!(", kernel {:?}", kernel));
+    }
+    if let Some(initrd) = &vm_config.initrd {
+        summary.push_str(&format!(", initrd {:?}", initrd));
+    }
+    summary
+}
+
 fn state_to_str(vm_state: VirtualMachineState) -> &'static str {
     match vm_state {
         VirtualMachineState::NOT_STARTED => "NOT_STARTED",
@@ -291,6 +370,7 @@ fn run(
     console_out_path: Option<&Path>,
     console_in_path: Option<&Path>,
     log_path: Option<&Path>,
+    notifier: Option<Arc<StartupNotifier>>,
 ) -> Result<(), Error> {
     let console_out = if let Some(console_out_path) = console_out_path {
         Some(File::create(console_out_path).with_context(|| {
@@ -315,10 +395,10 @@ fn run(
     } else {
         Some(duplicate_fd(io::stdout())?)
     };
-    let callback = Box::new(Callback {});
+    let callback = Box::new(Callback { notifier: notifier.clone() });
     let vm = VmInstance::create(service, config, console_out, console_in, log, Some(callback))
         .context("Failed to create VM")?;
-    vm.start().context("Failed to start VM")?;
+    start_and_notify(|| vm.start().context("Failed to start VM"), notifier.as_deref())?;
 
     let debug_level = get_debug_level(config).unwrap_or(DebugLevel::NONE);
 
@@ -337,6 +417,18 @@ fn run(
     Ok(())
 }
 
+/// Starts the VM with `start` then, if it succeeded, reports it to `notifier`.
+fn start_and_notify(
+    start: impl FnOnce() -> Result<(), Error>,
+    notifier: Option<&StartupNotifier>,
+) -> Result<(), Error> {
+    start()?;
+    if let Some(notifier) = notifier {
+        notifier.started();
+    }
+    Ok(())
+}
+
 fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<PathBuf>, Error> {
     let mut archive = ZipArchive::new(File::open(apk)?)?;
     let config_file = archive.by_name(config_path)?;
@@ -344,7 +436,10 @@ fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<PathBuf>, E
     Ok(config.extra_apks.into_iter().map(|x| x.path.into()).collect())
 }
 
-struct Callback {}
+struct Callback {
+    /// Set when running in the background, to report the startup of the VM.
+    notifier: Option<Arc<StartupNotifier>>,
+}
 
 impl vmclient::VmCallback for Callback {
     fn on_payload_started(&self, _cid: i32) {
@@ -353,6 +448,9 @@ impl vmclient::VmCallback for Callback {
 
     fn on_payload_ready(&self, _cid: i32) {
         eprintln!("payload is ready");
+        if let Some(notifier) = &self.notifier {
+            notifier.ready();
+        }
     }
 
     fn on_payload_finished(&self, _cid: i32, exit_code: i32) {
@@ -362,6 +460,12 @@ impl vmclient::VmCallback for Callback {
     fn on_error(&self, _cid: i32, error_code: ErrorCode, message: &str) {
         eprintln!("VM encountered an error: code={:?}, message={}", error_code, message);
     }
+
+    fn on_died(&self, _cid: i32, death_reason: DeathReason) {
+        if let Some(notifier) = &self.notifier {
+            notifier.died(death_reason);
+        }
+    }
 }
 
 /// Safely duplicate the file descriptor.
@@ -378,3 +482,180 @@ fn duplicate_fd<T: AsRawFd>(file: T) -> io::Result<File> {
         Ok(unsafe { File::from_raw_fd(dup_fd) })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{startup_channel, wait_for_startup};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{NamedTempFile, TempDir};
+    use vmclient::VmCallback;
+    use vmconfig::ConsoleType;
+
+    const CID: i32 = 42;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn load_config(json: &str) -> VmConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    const KERNEL_CONFIG: &str = r#"{
+        "kernel": "/data/local/tmp/config_kernel",
+        "initrd": "/data/local/tmp/config_initrd",
+        "platform_version": "~1.0"
+    }"#;
+
+    #[test]
+    fn no_overrides_keep_config() -> Result<(), Error> {
+        let mut vm_config = load_config(KERNEL_CONFIG);
+        override_kernel_and_initrd(&mut vm_config, None, None)?;
+        assert_eq!(vm_config, load_config(KERNEL_CONFIG));
+        Ok(())
+    }
+
+    #[test]
+    fn kernel_override_takes_precedence() -> Result<(), Error> {
+        let kernel = NamedTempFile::new()?;
+        let mut vm_config = load_config(KERNEL_CONFIG);
+        override_kernel_and_initrd(&mut vm_config, Some(kernel.path().to_owned()), None)?;
+        assert_eq!(vm_config.kernel.as_deref(), Some(kernel.path()));
+        assert_eq!(vm_config.initrd, Some(PathBuf::from("/data/local/tmp/config_initrd")));
+        Ok(())
+    }
+
+    #[test]
+    fn initrd_override_takes_precedence() -> Result<(), Error> {
+        let initrd = NamedTempFile::new()?;
+        let mut vm_config = load_config(KERNEL_CONFIG);
+        override_kernel_and_initrd(&mut vm_config, None, Some(initrd.path().to_owned()))?;
+        assert_eq!(vm_config.kernel, Some(PathBuf::from("/data/local/tmp/config_kernel")));
+        assert_eq!(vm_config.initrd.as_deref(), Some(initrd.path()));
+        Ok(())
+    }
+
+    #[test]
+    fn overrides_are_reflected_in_summary() -> Result<(), Error> {
+        let kernel = NamedTempFile::new()?;
+        let mut vm_config = load_config(KERNEL_CONFIG);
+        override_kernel_and_initrd(&mut vm_config, Some(kernel.path().to_owned()), None)?;
+        let summary = describe_custom_vm(Path::new("vm_config.json"), &vm_config);
+        assert_eq!(
+            summary,
+            format!(
+                "\"vm_config.json\", kernel {:?}, initrd \"/data/local/tmp/config_initrd\"",
+                kernel.path()
+            )
+        );
+
+        // Configs using a bootloader may still have an initrd, but no kernel.
+        let vm_config = load_config(
+            r#"{
+                "bootloader": "/data/local/tmp/bootloader",
+                "initrd": "/data/local/tmp/config_initrd",
+                "platform_version": "~1.0"
+            }"#,
+        );
+        let summary = describe_custom_vm(Path::new("vm_config.json"), &vm_config);
+        assert_eq!(summary, "\"vm_config.json\", initrd \"/data/local/tmp/config_initrd\"");
+        Ok(())
+    }
+
+    #[test]
+    fn override_conflicts_with_bootloader() -> Result<(), Error> {
+        let kernel = NamedTempFile::new()?;
+        let mut vm_config = load_config(
+            r#"{
+                "bootloader": "/data/local/tmp/bootloader",
+                "platform_version": "~1.0"
+            }"#,
+        );
+        let result =
+            override_kernel_and_initrd(&mut vm_config, Some(kernel.path().to_owned()), None);
+        assert!(result.is_err());
+        assert_eq!(vm_config.kernel, None);
+        Ok(())
+    }
+
+    #[test]
+    fn override_must_exist() {
+        let mut vm_config = load_config(KERNEL_CONFIG);
+        let result = override_kernel_and_initrd(
+            &mut vm_config,
+            Some(PathBuf::from("/data/local/tmp/does_not_exist")),
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn console_outputs_are_created() -> Result<(), Error> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("earlycon.log");
+        let consoles = [
+            ConsoleDevice {
+                console_type: ConsoleType::Serial,
+                earlycon: true,
+                path: Some(path.clone()),
+            },
+            ConsoleDevice { console_type: ConsoleType::VirtioConsole, earlycon: false, path: None },
+        ];
+
+        let outputs = open_console_outputs(&consoles)?;
+        assert!(outputs[0].is_some());
+        assert!(outputs[1].is_none());
+        assert!(path.exists());
+        Ok(())
+    }
+
+    /// Sets up the callback and notifier of a daemonized VM, as `run` does.
+    fn background_callback() -> Result<(Callback, Arc<StartupNotifier>, File), Error> {
+        let (notifier, events) = startup_channel()?;
+        let notifier = Arc::new(notifier);
+        Ok((Callback { notifier: Some(notifier.clone()) }, notifier, events))
+    }
+
+    #[test]
+    fn payload_ready_after_start() -> Result<(), Error> {
+        let (callback, notifier, events) = background_callback()?;
+        start_and_notify(|| Ok(()), Some(&notifier))?;
+        drop(notifier);
+
+        // Callbacks are delivered on binder threads, while the foreground process is waiting.
+        let binder_thread = thread::spawn(move || {
+            callback.on_payload_started(CID);
+            callback.on_payload_ready(CID);
+        });
+        wait_for_startup(events, true, TIMEOUT)?;
+        binder_thread.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn started_is_reported_only_after_start() -> Result<(), Error> {
+        let (callback, notifier, events) = background_callback()?;
+        let result = start_and_notify(|| bail!("Failed to start VM"), Some(&notifier));
+        assert!(result.is_err());
+        drop(callback);
+        drop(notifier);
+
+        let error = wait_for_startup(events, false, TIMEOUT).unwrap_err();
+        assert_eq!(error.to_string(), "The background process exited before the VM was started");
+        Ok(())
+    }
+
+    #[test]
+    fn vm_dies_before_ready() -> Result<(), Error> {
+        let (callback, notifier, events) = background_callback()?;
+        start_and_notify(|| Ok(()), Some(&notifier))?;
+        drop(notifier);
+
+        callback.on_payload_started(CID);
+        callback.on_payload_finished(CID, 1);
+        callback.on_died(CID, DeathReason::Shutdown);
+
+        let error = wait_for_startup(events, true, TIMEOUT).unwrap_err();
+        assert_eq!(error.to_string(), "VM died before it was ready: Shutdown");
+        Ok(())
+    }
+}
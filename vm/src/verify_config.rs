@@ -0,0 +1,58 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to check a VM config without running it
+
+use anyhow::{bail, Context, Error};
+use std::fs::File;
+use std::path::Path;
+use vmconfig::{DiskImage, VmConfig};
+
+/// Check that the given VM config file is valid and that all the files it references exist.
+pub fn command_verify_config(config_path: &Path) -> Result<(), Error> {
+    let config = verify_config(config_path)?;
+    println!("{:?} is a valid VM config with {} disk(s).", config_path, config.disks.len());
+    Ok(())
+}
+
+pub(crate) fn verify_config(config_path: &Path) -> Result<VmConfig, Error> {
+    let config_file = File::open(config_path).context("Failed to open config file")?;
+    let config = VmConfig::load(&config_file).context("Failed to parse config file")?;
+    for path in [&config.kernel, &config.initrd, &config.bootloader].into_iter().flatten() {
+        check_file(path)?;
+    }
+    for disk in &config.disks {
+        check_disk(disk)?;
+    }
+    Ok(config)
+}
+
+/// Check that the disk image is valid and that all the files it references exist.
+pub(crate) fn check_disk(disk: &DiskImage) -> Result<(), Error> {
+    disk.validate()?;
+    if let Some(image) = &disk.image {
+        check_file(image)?;
+    }
+    for partition in &disk.partitions {
+        check_file(&partition.path)?;
+    }
+    Ok(())
+}
+
+fn check_file(path: &Path) -> Result<(), Error> {
+    if !path.is_file() {
+        bail!("{:?} does not exist or is not a file", path);
+    }
+    Ok(())
+}
@@ -53,6 +53,12 @@ pub enum MemoryTrackerError {
     DuplicateMmioShare(usize),
     /// The MMIO_GUARD granule used by the hypervisor is not supported.
     UnsupportedMmioGuardGranule(usize),
+    /// Range to share is empty or not aligned to the memory sharing granule.
+    InvalidShareRange,
+    /// Range to share overlaps with the shared memory pool.
+    OverlapsSharedPool,
+    /// Attempting to share memory that overlaps a range already shared.
+    DuplicateShare(usize),
 }
 
 impl fmt::Display for MemoryTrackerError {
@@ -78,6 +84,13 @@ impl fmt::Display for MemoryTrackerError {
             Self::UnsupportedMmioGuardGranule(g) => {
                 write!(f, "Unsupported MMIO guard granule: {g}")
             }
+            Self::InvalidShareRange => {
+                write!(f, "Range to share is empty or not aligned to the sharing granule")
+            }
+            Self::OverlapsSharedPool => write!(f, "Range to share overlaps with the shared pool"),
+            Self::DuplicateShare(addr) => {
+                write!(f, "Attempted to share the memory range at {addr:#x} twice")
+            }
         }
     }
 }
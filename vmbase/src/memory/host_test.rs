@@ -0,0 +1,71 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the unit tests, on the host, of the memory modules that are written to be
+//! conditionally compiled with std: tracker.rs and sharing.rs.
+//!
+//! The rest of vmbase can't be built for the host so each of those modules is built as its own
+//! test crate, which includes this file to pull in the few modules it needs and stand-ins for the
+//! others. The crate root is expected to re-export `hyp` and `util` for `crate::` paths to resolve.
+
+// Each test crate only uses part of what's here.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+
+#[path = "error.rs"]
+pub mod error;
+#[path = "../util.rs"]
+pub mod util;
+
+/// Stand-in for the hypervisor interface, which isn't available on the host.
+pub mod hyp {
+    use core::fmt;
+
+    /// Stand-in for the errors reported by the hypervisor.
+    #[derive(Debug, Clone)]
+    pub struct Error;
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Hypervisor error")
+        }
+    }
+}
+
+/// Records the calls made to a mock, in order.
+pub struct CallLog<C>(RefCell<Vec<C>>);
+
+impl<C> Default for CallLog<C> {
+    fn default() -> Self {
+        Self(RefCell::new(Vec::new()))
+    }
+}
+
+impl<C: Clone> CallLog<C> {
+    /// Records that `call` was made.
+    pub fn record(&self, call: C) {
+        self.0.borrow_mut().push(call)
+    }
+
+    /// Returns the calls recorded so far.
+    pub fn to_vec(&self) -> Vec<C> {
+        self.0.borrow().clone()
+    }
+
+    /// Returns the calls recorded so far and forgets them.
+    pub fn take(&self) -> Vec<C> {
+        self.0.take()
+    }
+}
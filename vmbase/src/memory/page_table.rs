@@ -14,15 +14,13 @@
 
 //! Page table management.
 
+use super::tracker::{MemoryMapper, MMIO_LAZY_MAP_FLAG};
 use crate::read_sysreg;
 use aarch64_paging::idmap::IdMap;
 use aarch64_paging::paging::{Attributes, Constraints, Descriptor, MemoryRegion};
 use aarch64_paging::MapError;
 use core::result;
 
-/// Software bit used to indicate a device that should be lazily mapped.
-pub(super) const MMIO_LAZY_MAP_FLAG: Attributes = Attributes::SWFLAG_0;
-
 // We assume that:
 // - MAIR_EL1.Attr0 = "Device-nGnRE memory" (0b0000_0100)
 // - MAIR_EL1.Attr1 = "Normal memory, Outer & Inner WB Non-transient, R/W-Allocate" (0b1111_1111)
@@ -151,3 +149,35 @@ impl PageTable {
         self.idmap.walk_range(range, &mut callback)
     }
 }
+
+impl MemoryMapper for PageTable {
+    fn map_rodata(&mut self, range: &MemoryRegion) -> Result<()> {
+        PageTable::map_rodata(self, range)
+    }
+
+    fn map_data_dbm(&mut self, range: &MemoryRegion) -> Result<()> {
+        PageTable::map_data_dbm(self, range)
+    }
+
+    fn map_device(&mut self, range: &MemoryRegion) -> Result<()> {
+        PageTable::map_device(self, range)
+    }
+
+    fn map_device_lazy(&mut self, range: &MemoryRegion) -> Result<()> {
+        PageTable::map_device_lazy(self, range)
+    }
+
+    fn modify_range<F>(&mut self, range: &MemoryRegion, f: &F) -> Result<()>
+    where
+        F: Fn(&MemoryRegion, &mut Descriptor, usize) -> result::Result<(), ()>,
+    {
+        PageTable::modify_range(self, range, f)
+    }
+
+    fn walk_range<F>(&self, range: &MemoryRegion, f: &F) -> Result<()>
+    where
+        F: Fn(&MemoryRegion, &Descriptor, usize) -> result::Result<(), ()>,
+    {
+        PageTable::walk_range(self, range, f)
+    }
+}
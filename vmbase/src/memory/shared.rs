@@ -16,17 +16,16 @@
 
 use super::dbm::{flush_dirty_range, mark_dirty_block, set_dbm_enabled};
 use super::error::MemoryTrackerError;
-use super::page_table::{PageTable, MMIO_LAZY_MAP_FLAG};
+use super::page_table::PageTable;
+use super::sharing::{check_shareable, is_shared, GranuleSharer, RangeSharer};
+use super::tracker::{MemoryRange, RegionTracker};
 use super::util::{page_4kb_of, virt_to_phys};
 use crate::console;
 use crate::dsb;
 use crate::exceptions::HandleExceptionError;
 use crate::hyp::{self, get_mem_sharer, get_mmio_guard};
 use crate::util::unchecked_align_down;
-use crate::util::RangeExt as _;
-use aarch64_paging::paging::{
-    Attributes, Descriptor, MemoryRegion as VaRange, VirtualAddress, PAGE_SIZE,
-};
+use aarch64_paging::paging::{MemoryRegion as VaRange, VirtualAddress, PAGE_SIZE};
 use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
 use alloc::boxed::Box;
 use alloc::collections::BTreeSet;
@@ -39,54 +38,27 @@ use core::num::NonZeroUsize;
 use core::ops::Range;
 use core::ptr::NonNull;
 use core::result;
-use log::{debug, error, trace};
+use log::{debug, trace};
 use once_cell::race::OnceBox;
 use spin::mutex::SpinMutex;
-use tinyvec::ArrayVec;
 
 /// A global static variable representing the system memory tracker, protected by a spin mutex.
 pub static MEMORY: SpinMutex<Option<MemoryTracker>> = SpinMutex::new(None);
 
 static SHARED_POOL: OnceBox<LockedFrameAllocator<32>> = OnceBox::new();
 static SHARED_MEMORY: SpinMutex<Option<MemorySharer>> = SpinMutex::new(None);
-
-/// Memory range.
-pub type MemoryRange = Range<usize>;
-
-fn get_va_range(range: &MemoryRange) -> VaRange {
-    VaRange::new(range.start, range.end)
-}
+static SHARED_RANGES: SpinMutex<RangeSharer> = SpinMutex::new(RangeSharer::new());
+static STATIC_SHARED_POOL: SpinMutex<Option<MemoryRange>> = SpinMutex::new(None);
 
 type Result<T> = result::Result<T, MemoryTrackerError>;
 
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
-enum MemoryType {
-    #[default]
-    ReadOnly,
-    ReadWrite,
-}
-
-#[derive(Clone, Debug, Default)]
-struct MemoryRegion {
-    range: MemoryRange,
-    mem_type: MemoryType,
-}
-
 /// Tracks non-overlapping slices of main memory.
 pub struct MemoryTracker {
-    total: MemoryRange,
-    page_table: PageTable,
-    regions: ArrayVec<[MemoryRegion; MemoryTracker::CAPACITY]>,
-    mmio_regions: ArrayVec<[MemoryRange; MemoryTracker::MMIO_CAPACITY]>,
-    mmio_range: MemoryRange,
-    payload_range: Option<MemoryRange>,
+    regions: RegionTracker<PageTable>,
     mmio_sharer: MmioSharer,
 }
 
 impl MemoryTracker {
-    const CAPACITY: usize = 5;
-    const MMIO_CAPACITY: usize = 5;
-
     /// Creates a new instance from an active page table, covering the maximum RAM size.
     pub fn new(
         mut page_table: PageTable,
@@ -94,11 +66,6 @@ impl MemoryTracker {
         mmio_range: MemoryRange,
         payload_range: Option<Range<VirtualAddress>>,
     ) -> Self {
-        assert!(
-            !total.overlaps(&mmio_range),
-            "MMIO space should not overlap with the main memory region."
-        );
-
         // Activate dirty state management first, otherwise we may get permission faults immediately
         // after activating the new page table. This has no effect before the new page table is
         // activated because none of the entries in the initial idmap have the DBM flag.
@@ -110,13 +77,9 @@ impl MemoryTracker {
         unsafe { page_table.activate() }
         debug!("... Success!");
 
+        let payload_range = payload_range.map(|r| r.start.0..r.end.0);
         Self {
-            total,
-            page_table,
-            regions: ArrayVec::new(),
-            mmio_regions: ArrayVec::new(),
-            mmio_range,
-            payload_range: payload_range.map(|r| r.start.0..r.end.0),
+            regions: RegionTracker::new(page_table, total, mmio_range, payload_range),
             mmio_sharer: MmioSharer::new().unwrap(),
         }
     }
@@ -125,29 +88,12 @@ impl MemoryTracker {
     ///
     /// This function fails if it contains regions that are not included within the new size.
     pub fn shrink(&mut self, range: &MemoryRange) -> Result<()> {
-        if range.start != self.total.start {
-            return Err(MemoryTrackerError::DifferentBaseAddress);
-        }
-        if self.total.end < range.end {
-            return Err(MemoryTrackerError::SizeTooLarge);
-        }
-        if !self.regions.iter().all(|r| r.range.is_within(range)) {
-            return Err(MemoryTrackerError::SizeTooSmall);
-        }
-
-        self.total = range.clone();
-        Ok(())
+        self.regions.shrink(range)
     }
 
     /// Allocate the address range for a const slice; returns None if failed.
     pub fn alloc_range(&mut self, range: &MemoryRange) -> Result<MemoryRange> {
-        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadOnly };
-        self.check_allocatable(&region)?;
-        self.page_table.map_rodata(&get_va_range(range)).map_err(|e| {
-            error!("Error during range allocation: {e}");
-            MemoryTrackerError::FailedToMap
-        })?;
-        self.add(region)
+        self.regions.alloc_range(range)
     }
 
     /// Allocates the address range for a const slice.
@@ -160,24 +106,13 @@ impl MemoryTracker {
         &mut self,
         range: &MemoryRange,
     ) -> Result<MemoryRange> {
-        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadOnly };
-        self.check_no_overlap(&region)?;
-        self.page_table.map_rodata(&get_va_range(range)).map_err(|e| {
-            error!("Error during range allocation: {e}");
-            MemoryTrackerError::FailedToMap
-        })?;
-        self.add(region)
+        // SAFETY: The caller of this unsafe function upholds the same requirements.
+        unsafe { self.regions.alloc_range_outside_main_memory(range) }
     }
 
     /// Allocate the address range for a mutable slice; returns None if failed.
     pub fn alloc_range_mut(&mut self, range: &MemoryRange) -> Result<MemoryRange> {
-        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadWrite };
-        self.check_allocatable(&region)?;
-        self.page_table.map_data_dbm(&get_va_range(range)).map_err(|e| {
-            error!("Error during mutable range allocation: {e}");
-            MemoryTrackerError::FailedToMap
-        })?;
-        self.add(region)
+        self.regions.alloc_range_mut(range)
     }
 
     /// Allocate the address range for a const slice; returns None if failed.
@@ -193,64 +128,8 @@ impl MemoryTracker {
     /// Checks that the given range of addresses is within the MMIO region, and then maps it
     /// appropriately.
     pub fn map_mmio_range(&mut self, range: MemoryRange) -> Result<()> {
-        if !range.is_within(&self.mmio_range) {
-            return Err(MemoryTrackerError::OutOfRange);
-        }
-        if self.mmio_regions.iter().any(|r| range.overlaps(r)) {
-            return Err(MemoryTrackerError::Overlaps);
-        }
-        if self.mmio_regions.len() == self.mmio_regions.capacity() {
-            return Err(MemoryTrackerError::Full);
-        }
-
-        if get_mmio_guard().is_some() {
-            self.page_table.map_device_lazy(&get_va_range(&range)).map_err(|e| {
-                error!("Error during lazy MMIO device mapping: {e}");
-                MemoryTrackerError::FailedToMap
-            })?;
-        } else {
-            self.page_table.map_device(&get_va_range(&range)).map_err(|e| {
-                error!("Error during MMIO device mapping: {e}");
-                MemoryTrackerError::FailedToMap
-            })?;
-        }
-
-        if self.mmio_regions.try_push(range).is_some() {
-            return Err(MemoryTrackerError::Full);
-        }
-
-        Ok(())
-    }
-
-    /// Checks that the memory region meets the following criteria:
-    /// - It is within the range of the `MemoryTracker`.
-    /// - It does not overlap with any previously allocated regions.
-    /// - The `regions` ArrayVec has sufficient capacity to add it.
-    fn check_allocatable(&self, region: &MemoryRegion) -> Result<()> {
-        if !region.range.is_within(&self.total) {
-            return Err(MemoryTrackerError::OutOfRange);
-        }
-        self.check_no_overlap(region)
-    }
-
-    /// Checks that the given region doesn't overlap with any other previously allocated regions,
-    /// and that the regions ArrayVec has capacity to add it.
-    fn check_no_overlap(&self, region: &MemoryRegion) -> Result<()> {
-        if self.regions.iter().any(|r| region.range.overlaps(&r.range)) {
-            return Err(MemoryTrackerError::Overlaps);
-        }
-        if self.regions.len() == self.regions.capacity() {
-            return Err(MemoryTrackerError::Full);
-        }
-        Ok(())
-    }
-
-    fn add(&mut self, region: MemoryRegion) -> Result<MemoryRange> {
-        if self.regions.try_push(region).is_some() {
-            return Err(MemoryTrackerError::Full);
-        }
-
-        Ok(self.regions.last().unwrap().range.clone())
+        // With an MMIO guard, devices are only mapped once they are accessed and MMIO_GUARD_MAPed.
+        self.regions.map_mmio_range(range, get_mmio_guard().is_some())
     }
 
     /// Unshares any MMIO region previously shared with the MMIO guard.
@@ -288,11 +167,12 @@ impl MemoryTracker {
         let range = self.alloc_mut(range.start, size)?;
         let shared_pool = LockedFrameAllocator::<32>::new();
 
-        shared_pool.lock().insert(range);
+        shared_pool.lock().insert(range.clone());
 
         SHARED_POOL
             .set(Box::new(shared_pool))
             .map_err(|_| MemoryTrackerError::SharedPoolSetFailure)?;
+        STATIC_SHARED_POOL.lock().replace(range);
 
         Ok(())
     }
@@ -310,9 +190,35 @@ impl MemoryTracker {
         self.init_dynamic_shared_pool(size_of::<u8>())
     }
 
+    /// Shares with the host a range of tracked memory owned by the caller, without copying it
+    /// into the shared pool.
+    ///
+    /// The range must be aligned to the memory sharing granule and lie within a single tracked
+    /// region, without overlapping the shared pool or any range already shared this way. It stays
+    /// shared until the returned guard is dropped or `unshare_all_memory` is called.
+    pub fn share_range(&mut self, range: &MemoryRange) -> Result<SharedRangeGuard> {
+        let granule = match get_mem_sharer() {
+            Some(mem_sharer) => mem_sharer.granule()?,
+            None => PAGE_SIZE,
+        };
+        let static_pool = STATIC_SHARED_POOL.lock();
+        let shared_memory = SHARED_MEMORY.lock();
+        check_shareable(
+            range,
+            granule,
+            |r| self.regions.is_allocated(r),
+            static_pool.as_ref(),
+            shared_memory.as_ref().into_iter().flat_map(MemorySharer::frames),
+        )?;
+
+        let id = SHARED_RANGES.lock().share(&HypSharer, range, granule)?;
+        Ok(SharedRangeGuard { range: range.clone(), id })
+    }
+
     /// Unshares any memory that may have been shared.
     pub fn unshare_all_memory(&mut self) {
         drop(SHARED_MEMORY.lock().take());
+        SHARED_RANGES.lock().unshare_all(&HypSharer);
     }
 
     /// Handles translation fault for blocks flagged for lazy MMIO mapping by enabling the page
@@ -328,46 +234,23 @@ impl MemoryTracker {
     ///
     /// Returns an error if any PTE in the range is not an invalid lazy MMIO mapping.
     fn map_lazy_mmio_as_valid(&mut self, page_range: &VaRange) -> Result<()> {
-        // This must be safe and free from break-before-make (BBM) violations, given that the
-        // initial lazy mapping has the valid bit cleared, and each newly created valid descriptor
-        // created inside the mapping has the same size and alignment.
-        self.page_table
-            .modify_range(page_range, &|_: &VaRange, desc: &mut Descriptor, _: usize| {
-                let flags = desc.flags().expect("Unsupported PTE flags set");
-                if flags.contains(MMIO_LAZY_MAP_FLAG) && !flags.contains(Attributes::VALID) {
-                    desc.modify_flags(Attributes::VALID, Attributes::empty());
-                    Ok(())
-                } else {
-                    Err(())
-                }
-            })
-            .map_err(|_| MemoryTrackerError::InvalidPte)
+        self.regions.map_lazy_mmio_as_valid(page_range)
     }
 
     /// Flush all memory regions marked as writable-dirty.
     fn flush_dirty_pages(&mut self) -> Result<()> {
-        // Collect memory ranges for which dirty state is tracked.
-        let writable_regions =
-            self.regions.iter().filter(|r| r.mem_type == MemoryType::ReadWrite).map(|r| &r.range);
         // Execute a barrier instruction to ensure all hardware updates to the page table have been
         // observed before reading PTE flags to determine dirty state.
         dsb!("ish");
-        // Now flush writable-dirty pages in those regions.
-        for range in writable_regions.chain(self.payload_range.as_ref().into_iter()) {
-            self.page_table
-                .walk_range(&get_va_range(range), &flush_dirty_range)
-                .map_err(|_| MemoryTrackerError::FlushRegionFailed)?;
-        }
-        Ok(())
+        // Now flush writable-dirty pages in the memory ranges for which dirty state is tracked.
+        self.regions.flush_dirty_pages(&flush_dirty_range)
     }
 
     /// Handles permission fault for read-only blocks by setting writable-dirty state.
     /// In general, this should be called from the exception handler when hardware dirty
     /// state management is disabled or unavailable.
     fn handle_permission_fault(&mut self, addr: VirtualAddress) -> Result<()> {
-        self.page_table
-            .modify_range(&(addr..addr + 1).into(), &mark_dirty_block)
-            .map_err(|_| MemoryTrackerError::SetPteDirtyFailed)
+        self.regions.handle_permission_fault(addr, &mark_dirty_block)
     }
 }
 
@@ -504,6 +387,11 @@ impl MemorySharer {
         Self { granule, frames: Vec::with_capacity(capacity) }
     }
 
+    /// Returns the address ranges of the frames shared by this instance.
+    fn frames(&self) -> impl Iterator<Item = MemoryRange> + '_ {
+        self.frames.iter().map(|&(base, layout)| base..(base + layout.size()))
+    }
+
     /// Gets from the global allocator a granule-aligned region that suits `hint` and share it.
     fn refill(&mut self, pool: &mut FrameAllocator<32>, hint: Layout) {
         let layout = hint.align_to(self.granule).unwrap().pad_to_align();
@@ -547,6 +435,78 @@ impl Drop for MemorySharer {
     }
 }
 
+/// Returns whether the whole of `range` is shared with the host, through either the shared pool or
+/// `MemoryTracker::share_range`.
+pub fn is_shared_range(range: &MemoryRange) -> bool {
+    let static_pool = STATIC_SHARED_POOL.lock();
+    let shared_memory = SHARED_MEMORY.lock();
+    let shared_ranges = SHARED_RANGES.lock();
+
+    is_shared(
+        range,
+        static_pool.as_ref(),
+        || shared_memory.as_ref().into_iter().flat_map(MemorySharer::frames),
+        &shared_ranges,
+    )
+}
+
+/// Panics if `range` isn't shared with the host, when debug assertions are enabled.
+///
+/// This is meant to be called before handing an address to the host, e.g. in a virtio descriptor.
+pub(crate) fn debug_assert_shared(range: &MemoryRange) {
+    if cfg!(debug_assertions) {
+        assert!(is_shared_range(range), "Memory range {range:#x?} is not shared with the host");
+    }
+}
+
+/// Keeps a range of memory shared with the host for as long as it is alive.
+///
+/// Returned by `MemoryTracker::share_range`.
+#[must_use]
+pub struct SharedRangeGuard {
+    range: MemoryRange,
+    id: usize,
+}
+
+impl SharedRangeGuard {
+    /// Returns the range of memory shared with the host.
+    pub fn range(&self) -> &MemoryRange {
+        &self.range
+    }
+}
+
+impl Drop for SharedRangeGuard {
+    fn drop(&mut self) {
+        // Does nothing if the range was already unshared by `unshare_all_memory()`.
+        SHARED_RANGES.lock().unshare(&HypSharer, self.id);
+    }
+}
+
+/// Shares memory through the hypervisor, if it requires memory to be shared with the host.
+struct HypSharer;
+
+impl HypSharer {
+    fn base_ipa(vaddr: usize) -> u64 {
+        virt_to_phys(NonNull::new(vaddr as *mut _).unwrap()).try_into().unwrap()
+    }
+}
+
+impl GranuleSharer for HypSharer {
+    fn share(&self, vaddr: usize) -> Result<()> {
+        if let Some(mem_sharer) = get_mem_sharer() {
+            mem_sharer.share(Self::base_ipa(vaddr))?;
+        }
+        Ok(())
+    }
+
+    fn unshare(&self, vaddr: usize) -> Result<()> {
+        if let Some(mem_sharer) = get_mem_sharer() {
+            mem_sharer.unshare(Self::base_ipa(vaddr))?;
+        }
+        Ok(())
+    }
+}
+
 /// Handles a translation fault with the given fault address register (FAR).
 #[inline]
 pub fn handle_translation_fault(far: VirtualAddress) -> result::Result<(), HandleExceptionError> {
@@ -0,0 +1,459 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bookkeeping of the memory ranges shared with the host by `MemoryTracker::share_range`,
+//! independent of the hypervisor in use. Written to be conditionally compiled with std, see
+//! host_test.rs.
+
+#[cfg(not(test))]
+use super::error::MemoryTrackerError;
+#[cfg(not(test))]
+use super::tracker::MemoryRange;
+use crate::util::RangeExt as _;
+#[cfg(not(test))]
+use alloc::collections::BTreeMap;
+use core::result;
+#[cfg(test)]
+use host_test::error::MemoryTrackerError;
+#[cfg(test)]
+use host_test::{hyp, util};
+use log::trace;
+#[cfg(test)]
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+#[path = "host_test.rs"]
+mod host_test;
+
+/// Memory range, as defined by tracker.rs.
+#[cfg(test)]
+type MemoryRange = core::ops::Range<usize>;
+
+type Result<T> = result::Result<T, MemoryTrackerError>;
+
+/// Hypervisor operations through which `RangeSharer` shares memory with the host.
+pub trait GranuleSharer {
+    /// Shares with the host the granule of memory starting at the virtual address `vaddr`.
+    fn share(&self, vaddr: usize) -> Result<()>;
+
+    /// Revokes the access of the host to the granule of memory starting at the virtual address
+    /// `vaddr`, previously shared with `share`.
+    fn unshare(&self, vaddr: usize) -> Result<()>;
+}
+
+/// Checks that `range` may be shared with the host by `RangeSharer::share`.
+///
+/// The range must be aligned to `granule`, lie within a single region for which `is_allocated`
+/// returns true and not overlap the shared pool, i.e. the `static_pool` or the `dynamic_frames`
+/// shared to grow it.
+pub fn check_shareable(
+    range: &MemoryRange,
+    granule: usize,
+    is_allocated: impl FnOnce(&MemoryRange) -> bool,
+    static_pool: Option<&MemoryRange>,
+    mut dynamic_frames: impl Iterator<Item = MemoryRange>,
+) -> Result<()> {
+    if range.is_empty()
+        || !range.start.is_multiple_of(granule)
+        || !range.end.is_multiple_of(granule)
+    {
+        return Err(MemoryTrackerError::InvalidShareRange);
+    }
+    if !is_allocated(range) {
+        return Err(MemoryTrackerError::OutOfRange);
+    }
+    if static_pool.is_some_and(|pool| range.overlaps(pool)) {
+        return Err(MemoryTrackerError::OverlapsSharedPool);
+    }
+    if dynamic_frames.any(|frame| range.overlaps(&frame)) {
+        return Err(MemoryTrackerError::OverlapsSharedPool);
+    }
+    Ok(())
+}
+
+/// Returns whether the whole of `range` is shared with the host, through either the shared pool,
+/// i.e. the `static_pool` or the `dynamic_frames` shared to grow it, or the `range_sharer`.
+pub fn is_shared<I>(
+    range: &MemoryRange,
+    static_pool: Option<&MemoryRange>,
+    dynamic_frames: impl Fn() -> I,
+    range_sharer: &RangeSharer,
+) -> bool
+where
+    I: Iterator<Item = MemoryRange>,
+{
+    if range.is_empty() {
+        return false;
+    }
+    if static_pool.is_some_and(|pool| range.is_within(pool)) {
+        return true;
+    }
+
+    is_covered(range, || dynamic_frames().chain(range_sharer.ranges().cloned()))
+}
+
+/// Returns whether every address of `range` lies within one of the ranges returned by `shared`,
+/// which may be adjacent to each other.
+fn is_covered<I>(range: &MemoryRange, shared: impl Fn() -> I) -> bool
+where
+    I: Iterator<Item = MemoryRange>,
+{
+    let mut covered = range.start;
+    while covered < range.end {
+        let Some(next) = shared().find(|r| r.contains(&covered)) else {
+            return false;
+        };
+        covered = next.end;
+    }
+    true
+}
+
+/// A caller-owned memory range shared with the host, one granule at a time.
+struct SharedRange {
+    range: MemoryRange,
+    granule: usize,
+}
+
+impl SharedRange {
+    fn granules(&self) -> impl Iterator<Item = usize> {
+        self.range.clone().step_by(self.granule)
+    }
+}
+
+/// Tracks the ranges shared through `MemoryTracker::share_range`.
+#[derive(Default)]
+pub struct RangeSharer {
+    next_id: usize,
+    ranges: BTreeMap<usize, SharedRange>,
+}
+
+impl RangeSharer {
+    /// Creates an instance that isn't tracking any range.
+    pub const fn new() -> Self {
+        Self { next_id: 0, ranges: BTreeMap::new() }
+    }
+
+    /// Shares `range` with the host through `sharer`, one `granule` at a time, and returns the ID
+    /// under which it is tracked.
+    ///
+    /// The range is either fully shared or, on error, not at all.
+    pub fn share(
+        &mut self,
+        sharer: &impl GranuleSharer,
+        range: &MemoryRange,
+        granule: usize,
+    ) -> Result<usize> {
+        if let Some(shared) = self.ranges.values().find(|r| r.range.overlaps(range)) {
+            return Err(MemoryTrackerError::DuplicateShare(shared.range.start));
+        }
+
+        let shared = SharedRange { range: range.clone(), granule };
+        trace!("Sharing memory range {range:#x?}");
+        for (i, vaddr) in shared.granules().enumerate() {
+            if let Err(e) = sharer.share(vaddr) {
+                // Roll back so that the range is either fully shared or not at all.
+                for vaddr in shared.granules().take(i) {
+                    sharer.unshare(vaddr).unwrap();
+                }
+                return Err(e);
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ranges.insert(id, shared);
+        Ok(id)
+    }
+
+    /// Unshares the range tracked under `id`, if it is still shared.
+    pub fn unshare(&mut self, sharer: &impl GranuleSharer, id: usize) {
+        if let Some(shared) = self.ranges.remove(&id) {
+            Self::unshare_range(sharer, &shared);
+        }
+    }
+
+    /// Unshares all the ranges being tracked.
+    pub fn unshare_all(&mut self, sharer: &impl GranuleSharer) {
+        while let Some((_, shared)) = self.ranges.pop_first() {
+            Self::unshare_range(sharer, &shared);
+        }
+    }
+
+    /// Returns the ranges currently shared.
+    pub fn ranges(&self) -> impl Iterator<Item = &MemoryRange> {
+        self.ranges.values().map(|shared| &shared.range)
+    }
+
+    fn unshare_range(sharer: &impl GranuleSharer, shared: &SharedRange) {
+        trace!("Unsharing memory range {:#x?}", shared.range);
+        for vaddr in shared.granules() {
+            sharer.unshare(vaddr).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use host_test::CallLog;
+
+    const GRANULE: usize = 0x1000;
+    const HEAP: MemoryRange = 0x8000_0000..0x8010_0000;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Call {
+        Share(usize),
+        Unshare(usize),
+    }
+
+    /// Records the calls made to it, failing to share the granule at `fail_at`, if any.
+    #[derive(Default)]
+    struct MockSharer {
+        calls: CallLog<Call>,
+        fail_at: Option<usize>,
+    }
+
+    impl MockSharer {
+        fn failing_at(vaddr: usize) -> Self {
+            Self { fail_at: Some(vaddr), ..Default::default() }
+        }
+
+        fn take_calls(&self) -> Vec<Call> {
+            self.calls.take()
+        }
+    }
+
+    impl GranuleSharer for MockSharer {
+        fn share(&self, vaddr: usize) -> Result<()> {
+            if self.fail_at == Some(vaddr) {
+                return Err(MemoryTrackerError::FailedToMap);
+            }
+            self.calls.record(Call::Share(vaddr));
+            Ok(())
+        }
+
+        fn unshare(&self, vaddr: usize) -> Result<()> {
+            self.calls.record(Call::Unshare(vaddr));
+            Ok(())
+        }
+    }
+
+    fn granules(start: usize, count: usize) -> MemoryRange {
+        start..(start + count * GRANULE)
+    }
+
+    fn check(range: &MemoryRange, static_pool: Option<&MemoryRange>) -> Result<()> {
+        check_shareable(range, GRANULE, |r| r.is_within(&HEAP), static_pool, core::iter::empty())
+    }
+
+    #[test]
+    fn check_shareable_range() {
+        let pool = granules(HEAP.start, 4);
+        let frames = [granules(HEAP.end - 2 * GRANULE, 2)];
+
+        let range = granules(pool.end, 2);
+        let is_allocated = |r: &MemoryRange| r.is_within(&HEAP);
+        check_shareable(&range, GRANULE, is_allocated, Some(&pool), frames.into_iter()).unwrap();
+    }
+
+    #[test]
+    fn check_shareable_invalid_range() {
+        for range in [
+            HEAP.start..HEAP.start,
+            (HEAP.start + 8)..(HEAP.start + GRANULE),
+            HEAP.start..(HEAP.start + GRANULE + 8),
+        ] {
+            let result = check(&range, None);
+            assert!(matches!(result, Err(MemoryTrackerError::InvalidShareRange)), "{range:#x?}");
+        }
+    }
+
+    #[test]
+    fn check_shareable_out_of_range() {
+        let result = check(&granules(HEAP.end - GRANULE, 2), None);
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+    }
+
+    #[test]
+    fn check_shareable_overlapping_static_pool() {
+        let pool = granules(HEAP.start + 2 * GRANULE, 2);
+
+        let result = check(&granules(HEAP.start, 3), Some(&pool));
+        assert!(matches!(result, Err(MemoryTrackerError::OverlapsSharedPool)));
+        // Adjacent ranges don't overlap.
+        check(&granules(HEAP.start, 2), Some(&pool)).unwrap();
+        check(&granules(pool.end, 2), Some(&pool)).unwrap();
+    }
+
+    #[test]
+    fn check_shareable_overlapping_dynamic_frames() {
+        let frames = [granules(HEAP.start, 1), granules(HEAP.start + 4 * GRANULE, 2)];
+        let is_allocated = |r: &MemoryRange| r.is_within(&HEAP);
+
+        let range = granules(HEAP.start + 5 * GRANULE, 2);
+        let result =
+            check_shareable(&range, GRANULE, is_allocated, None, frames.clone().into_iter());
+        assert!(matches!(result, Err(MemoryTrackerError::OverlapsSharedPool)));
+        let range = granules(HEAP.start + GRANULE, 3);
+        check_shareable(&range, GRANULE, is_allocated, None, frames.into_iter()).unwrap();
+    }
+
+    #[test]
+    fn share_and_unshare() {
+        let sharer = MockSharer::default();
+        let mut ranges = RangeSharer::new();
+        let first = granules(HEAP.start, 2);
+        let second = granules(first.end, 1);
+
+        let first_id = ranges.share(&sharer, &first, GRANULE).unwrap();
+        let second_id = ranges.share(&sharer, &second, GRANULE).unwrap();
+        assert_ne!(first_id, second_id);
+        assert_eq!(
+            sharer.take_calls(),
+            [
+                Call::Share(first.start),
+                Call::Share(first.start + GRANULE),
+                Call::Share(second.start)
+            ]
+        );
+        assert_eq!(ranges.ranges().collect::<Vec<_>>(), [&first, &second]);
+
+        ranges.unshare(&sharer, first_id);
+        assert_eq!(
+            sharer.take_calls(),
+            [Call::Unshare(first.start), Call::Unshare(first.start + GRANULE)]
+        );
+        assert_eq!(ranges.ranges().collect::<Vec<_>>(), [&second]);
+    }
+
+    #[test]
+    fn share_duplicate() {
+        let sharer = MockSharer::default();
+        let mut ranges = RangeSharer::new();
+        let range = granules(HEAP.start, 2);
+        ranges.share(&sharer, &range, GRANULE).unwrap();
+        sharer.take_calls();
+
+        let result = ranges.share(&sharer, &granules(range.start + GRANULE, 2), GRANULE);
+        assert!(matches!(result, Err(MemoryTrackerError::DuplicateShare(a)) if a == range.start));
+        assert!(sharer.take_calls().is_empty());
+    }
+
+    #[test]
+    fn share_rolls_back_on_failure() {
+        let range = granules(HEAP.start, 3);
+        let sharer = MockSharer::failing_at(range.start + 2 * GRANULE);
+        let mut ranges = RangeSharer::new();
+
+        let result = ranges.share(&sharer, &range, GRANULE);
+        assert!(matches!(result, Err(MemoryTrackerError::FailedToMap)));
+        assert_eq!(
+            sharer.take_calls(),
+            [
+                Call::Share(range.start),
+                Call::Share(range.start + GRANULE),
+                Call::Unshare(range.start),
+                Call::Unshare(range.start + GRANULE),
+            ]
+        );
+        assert_eq!(ranges.ranges().count(), 0);
+        // Nothing is left behind that would prevent sharing the range again.
+        ranges.share(&MockSharer::default(), &range, GRANULE).unwrap();
+    }
+
+    #[test]
+    fn unshare_after_unshare_all() {
+        let sharer = MockSharer::default();
+        let mut ranges = RangeSharer::new();
+        let first = granules(HEAP.start, 1);
+        let second = granules(HEAP.start + 4 * GRANULE, 1);
+        let first_id = ranges.share(&sharer, &first, GRANULE).unwrap();
+        ranges.share(&sharer, &second, GRANULE).unwrap();
+        sharer.take_calls();
+
+        ranges.unshare_all(&sharer);
+        assert_eq!(sharer.take_calls(), [Call::Unshare(first.start), Call::Unshare(second.start)]);
+
+        // As when a SharedRangeGuard is dropped after MemoryTracker::unshare_all_memory().
+        ranges.unshare(&sharer, first_id);
+        assert!(sharer.take_calls().is_empty());
+    }
+
+    #[test]
+    fn is_covered_by_adjacent_ranges() {
+        let shared = || [0x3000..0x4000, 0x1000..0x2000, 0x2000..0x3000].into_iter();
+
+        assert!(is_covered(&(0x1000..0x4000), shared));
+        assert!(is_covered(&(0x1800..0x2800), shared));
+        assert!(!is_covered(&(0x0..0x2000), shared));
+        assert!(!is_covered(&(0x3000..0x5000), shared));
+        assert!(!is_covered(&(0x1000..0x2000), || [0x1000..0x1800, 0x1900..0x2000].into_iter()));
+        assert!(!is_covered(&(0x1000..0x2000), core::iter::empty));
+    }
+
+    #[test]
+    fn is_shared_within_static_pool() {
+        let pool = granules(HEAP.start, 4);
+        let ranges = RangeSharer::new();
+        let is_shared = |range| is_shared(&range, Some(&pool), core::iter::empty, &ranges);
+
+        assert!(is_shared(pool.clone()));
+        assert!(is_shared((pool.start + 8)..(pool.start + GRANULE + 8)));
+        assert!(!is_shared((pool.end - GRANULE)..(pool.end + 8)));
+        assert!(!is_shared(granules(pool.end, 1)));
+        assert!(!is_shared(pool.start..pool.start));
+    }
+
+    #[test]
+    fn is_shared_within_dynamic_frames() {
+        let frames = [granules(HEAP.start + 4 * GRANULE, 2), granules(HEAP.start, 2)];
+        let ranges = RangeSharer::new();
+        let is_shared = |range| is_shared(&range, None, || frames.clone().into_iter(), &ranges);
+
+        assert!(is_shared(granules(HEAP.start + 4 * GRANULE, 2)));
+        assert!(is_shared((HEAP.start + 8)..(HEAP.start + 16)));
+        assert!(!is_shared(granules(HEAP.start + GRANULE, 2)));
+        assert!(!is_shared(granules(HEAP.start + 2 * GRANULE, 1)));
+    }
+
+    #[test]
+    fn is_shared_within_shared_ranges() {
+        let sharer = MockSharer::default();
+        let mut ranges = RangeSharer::new();
+        let range = granules(HEAP.start, 2);
+        let id = ranges.share(&sharer, &range, GRANULE).unwrap();
+
+        assert!(is_shared(&range, None, core::iter::empty, &ranges));
+        assert!(is_shared(&granules(range.start + GRANULE, 1), None, core::iter::empty, &ranges));
+        assert!(!is_shared(&granules(range.start + GRANULE, 2), None, core::iter::empty, &ranges));
+        assert!(!is_shared(&granules(range.end, 1), None, core::iter::empty, &ranges));
+
+        ranges.unshare(&sharer, id);
+        assert!(!is_shared(&range, None, core::iter::empty, &ranges));
+    }
+
+    #[test]
+    fn is_shared_across_frames_and_shared_ranges() {
+        let frames = [granules(HEAP.start, 2)];
+        let mut ranges = RangeSharer::new();
+        ranges
+            .share(&MockSharer::default(), &granules(HEAP.start + 2 * GRANULE, 2), GRANULE)
+            .unwrap();
+        let is_shared = |range| is_shared(&range, None, || frames.clone().into_iter(), &ranges);
+
+        assert!(is_shared(granules(HEAP.start + GRANULE, 2)));
+        assert!(is_shared(granules(HEAP.start, 4)));
+        assert!(!is_shared(granules(HEAP.start + GRANULE, 4)));
+    }
+}
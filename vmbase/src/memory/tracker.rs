@@ -0,0 +1,638 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bookkeeping of the memory mapped by `MemoryTracker`, independent of the page table in use.
+//! Written to be conditionally compiled with std, see host_test.rs.
+
+#[cfg(not(test))]
+use super::error::MemoryTrackerError;
+use crate::util::RangeExt as _;
+use aarch64_paging::paging::{Attributes, Descriptor, MemoryRegion as VaRange, VirtualAddress};
+use aarch64_paging::MapError;
+use core::ops::Range;
+use core::result;
+#[cfg(test)]
+use host_test::error::MemoryTrackerError;
+#[cfg(test)]
+use host_test::{hyp, util};
+use log::error;
+use tinyvec::ArrayVec;
+
+#[cfg(test)]
+#[path = "host_test.rs"]
+mod host_test;
+
+/// Software bit used to indicate a device that should be lazily mapped.
+pub const MMIO_LAZY_MAP_FLAG: Attributes = Attributes::SWFLAG_0;
+
+/// Memory range.
+pub type MemoryRange = Range<usize>;
+
+type Result<T> = result::Result<T, MemoryTrackerError>;
+
+const CAPACITY: usize = 5;
+const MMIO_CAPACITY: usize = 5;
+
+/// Page table operations through which `RegionTracker` maps memory.
+pub trait MemoryMapper {
+    /// Maps the given range of virtual addresses to the physical addresses as non-executable
+    /// and read-only normal memory.
+    fn map_rodata(&mut self, range: &VaRange) -> result::Result<(), MapError>;
+
+    /// Maps the given range of virtual addresses to the physical addresses as non-executable,
+    /// read-only and writable-clean normal memory.
+    fn map_data_dbm(&mut self, range: &VaRange) -> result::Result<(), MapError>;
+
+    /// Maps the given range of virtual addresses to the physical addresses as valid device
+    /// memory.
+    fn map_device(&mut self, range: &VaRange) -> result::Result<(), MapError>;
+
+    /// Maps the given range of virtual addresses to the physical addresses as lazily mapped
+    /// device memory.
+    fn map_device_lazy(&mut self, range: &VaRange) -> result::Result<(), MapError>;
+
+    /// Applies the provided updater function to a number of PTEs corresponding to a given memory
+    /// range.
+    fn modify_range<F>(&mut self, range: &VaRange, f: &F) -> result::Result<(), MapError>
+    where
+        F: Fn(&VaRange, &mut Descriptor, usize) -> result::Result<(), ()>;
+
+    /// Applies the provided callback function to a number of PTEs corresponding to a given memory
+    /// range.
+    fn walk_range<F>(&self, range: &VaRange, f: &F) -> result::Result<(), MapError>
+    where
+        F: Fn(&VaRange, &Descriptor, usize) -> result::Result<(), ()>;
+}
+
+fn get_va_range(range: &MemoryRange) -> VaRange {
+    VaRange::new(range.start, range.end)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum MemoryType {
+    #[default]
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Clone, Debug, Default)]
+struct MemoryRegion {
+    range: MemoryRange,
+    mem_type: MemoryType,
+}
+
+/// Tracks non-overlapping slices of main memory and MMIO regions, mapped through a page table.
+pub struct RegionTracker<P: MemoryMapper> {
+    total: MemoryRange,
+    page_table: P,
+    regions: ArrayVec<[MemoryRegion; CAPACITY]>,
+    mmio_regions: ArrayVec<[MemoryRange; MMIO_CAPACITY]>,
+    mmio_range: MemoryRange,
+    payload_range: Option<MemoryRange>,
+}
+
+impl<P: MemoryMapper> RegionTracker<P> {
+    /// Creates a new instance mapping memory through `page_table`, covering the maximum RAM size.
+    pub fn new(
+        page_table: P,
+        total: MemoryRange,
+        mmio_range: MemoryRange,
+        payload_range: Option<MemoryRange>,
+    ) -> Self {
+        assert!(
+            !total.overlaps(&mmio_range),
+            "MMIO space should not overlap with the main memory region."
+        );
+
+        Self {
+            total,
+            page_table,
+            regions: ArrayVec::new(),
+            mmio_regions: ArrayVec::new(),
+            mmio_range,
+            payload_range,
+        }
+    }
+
+    /// Resize the total RAM size.
+    ///
+    /// This function fails if it contains regions that are not included within the new size.
+    pub fn shrink(&mut self, range: &MemoryRange) -> Result<()> {
+        if range.start != self.total.start {
+            return Err(MemoryTrackerError::DifferentBaseAddress);
+        }
+        if self.total.end < range.end {
+            return Err(MemoryTrackerError::SizeTooLarge);
+        }
+        if !self.regions.iter().all(|r| r.range.is_within(range)) {
+            return Err(MemoryTrackerError::SizeTooSmall);
+        }
+
+        self.total = range.clone();
+        Ok(())
+    }
+
+    /// Allocate the address range for a const slice.
+    pub fn alloc_range(&mut self, range: &MemoryRange) -> Result<MemoryRange> {
+        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadOnly };
+        self.check_allocatable(&region)?;
+        self.page_table.map_rodata(&get_va_range(range)).map_err(|e| {
+            error!("Error during range allocation: {e}");
+            MemoryTrackerError::FailedToMap
+        })?;
+        self.add(region)
+    }
+
+    /// Allocates the address range for a const slice.
+    ///
+    /// # Safety
+    ///
+    /// Callers of this method need to ensure that the `range` is valid for mapping as read-only
+    /// data.
+    pub unsafe fn alloc_range_outside_main_memory(
+        &mut self,
+        range: &MemoryRange,
+    ) -> Result<MemoryRange> {
+        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadOnly };
+        self.check_no_overlap(&region)?;
+        self.page_table.map_rodata(&get_va_range(range)).map_err(|e| {
+            error!("Error during range allocation: {e}");
+            MemoryTrackerError::FailedToMap
+        })?;
+        self.add(region)
+    }
+
+    /// Allocate the address range for a mutable slice.
+    pub fn alloc_range_mut(&mut self, range: &MemoryRange) -> Result<MemoryRange> {
+        let region = MemoryRegion { range: range.clone(), mem_type: MemoryType::ReadWrite };
+        self.check_allocatable(&region)?;
+        self.page_table.map_data_dbm(&get_va_range(range)).map_err(|e| {
+            error!("Error during mutable range allocation: {e}");
+            MemoryTrackerError::FailedToMap
+        })?;
+        self.add(region)
+    }
+
+    /// Checks that the given range of addresses is within the MMIO region, and then maps it as
+    /// device memory, to be made valid on first access if `lazy`.
+    pub fn map_mmio_range(&mut self, range: MemoryRange, lazy: bool) -> Result<()> {
+        if !range.is_within(&self.mmio_range) {
+            return Err(MemoryTrackerError::OutOfRange);
+        }
+        if self.mmio_regions.iter().any(|r| range.overlaps(r)) {
+            return Err(MemoryTrackerError::Overlaps);
+        }
+        if self.mmio_regions.len() == self.mmio_regions.capacity() {
+            return Err(MemoryTrackerError::Full);
+        }
+
+        if lazy {
+            self.page_table.map_device_lazy(&get_va_range(&range)).map_err(|e| {
+                error!("Error during lazy MMIO device mapping: {e}");
+                MemoryTrackerError::FailedToMap
+            })?;
+        } else {
+            self.page_table.map_device(&get_va_range(&range)).map_err(|e| {
+                error!("Error during MMIO device mapping: {e}");
+                MemoryTrackerError::FailedToMap
+            })?;
+        }
+
+        if self.mmio_regions.try_push(range).is_some() {
+            return Err(MemoryTrackerError::Full);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether `range` lies within a single allocated region.
+    pub fn is_allocated(&self, range: &MemoryRange) -> bool {
+        self.regions.iter().any(|r| range.is_within(&r.range))
+    }
+
+    /// Checks that the memory region meets the following criteria:
+    /// - It is within the range of the `RegionTracker`.
+    /// - It does not overlap with any previously allocated regions.
+    /// - The `regions` ArrayVec has sufficient capacity to add it.
+    fn check_allocatable(&self, region: &MemoryRegion) -> Result<()> {
+        if !region.range.is_within(&self.total) {
+            return Err(MemoryTrackerError::OutOfRange);
+        }
+        self.check_no_overlap(region)
+    }
+
+    /// Checks that the given region doesn't overlap with any other previously allocated regions,
+    /// and that the regions ArrayVec has capacity to add it.
+    fn check_no_overlap(&self, region: &MemoryRegion) -> Result<()> {
+        if self.regions.iter().any(|r| region.range.overlaps(&r.range)) {
+            return Err(MemoryTrackerError::Overlaps);
+        }
+        if self.regions.len() == self.regions.capacity() {
+            return Err(MemoryTrackerError::Full);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, region: MemoryRegion) -> Result<MemoryRange> {
+        if self.regions.try_push(region).is_some() {
+            return Err(MemoryTrackerError::Full);
+        }
+
+        Ok(self.regions.last().unwrap().range.clone())
+    }
+
+    /// Modify the PTEs corresponding to a given range from (invalid) "lazy MMIO" to valid MMIO.
+    ///
+    /// Returns an error if any PTE in the range is not an invalid lazy MMIO mapping.
+    pub fn map_lazy_mmio_as_valid(&mut self, page_range: &VaRange) -> Result<()> {
+        // This must be safe and free from break-before-make (BBM) violations, given that the
+        // initial lazy mapping has the valid bit cleared, and each newly created valid descriptor
+        // created inside the mapping has the same size and alignment.
+        self.page_table
+            .modify_range(page_range, &|_: &VaRange, desc: &mut Descriptor, _: usize| {
+                let flags = desc.flags().expect("Unsupported PTE flags set");
+                if flags.contains(MMIO_LAZY_MAP_FLAG) && !flags.contains(Attributes::VALID) {
+                    desc.modify_flags(Attributes::VALID, Attributes::empty());
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            })
+            .map_err(|_| MemoryTrackerError::InvalidPte)
+    }
+
+    /// Applies `flush_dirty_range` to the PTEs of all memory for which dirty state is tracked.
+    ///
+    /// The caller is responsible for ensuring that hardware updates to the page table have been
+    /// observed before calling this.
+    pub fn flush_dirty_pages<F>(&self, flush_dirty_range: &F) -> Result<()>
+    where
+        F: Fn(&VaRange, &Descriptor, usize) -> result::Result<(), ()>,
+    {
+        let writable_regions =
+            self.regions.iter().filter(|r| r.mem_type == MemoryType::ReadWrite).map(|r| &r.range);
+        for range in writable_regions.chain(self.payload_range.as_ref()) {
+            self.page_table
+                .walk_range(&get_va_range(range), flush_dirty_range)
+                .map_err(|_| MemoryTrackerError::FlushRegionFailed)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `mark_dirty_block` to the PTE of the read-only block containing `addr`, to set its
+    /// writable-dirty state.
+    pub fn handle_permission_fault<F>(
+        &mut self,
+        addr: VirtualAddress,
+        mark_dirty_block: &F,
+    ) -> Result<()>
+    where
+        F: Fn(&VaRange, &mut Descriptor, usize) -> result::Result<(), ()>,
+    {
+        self.page_table
+            .modify_range(&(addr..addr + 1).into(), mark_dirty_block)
+            .map_err(|_| MemoryTrackerError::SetPteDirtyFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aarch64_paging::idmap::IdMap;
+    use aarch64_paging::paging::Constraints;
+    use host_test::CallLog;
+    use std::cell::RefCell;
+
+    const PAGE_SIZE: usize = 0x1000;
+    const TOTAL: MemoryRange = 0x8000_0000..0x8010_0000;
+    const MMIO: MemoryRange = 0x0..0x4000_0000;
+    const PAYLOAD: MemoryRange = 0x9000_0000..0x9000_4000;
+
+    // Simplified versions of the attributes used by the real page table.
+    const RODATA: Attributes = Attributes::VALID.union(Attributes::READ_ONLY);
+    const DATA_DBM: Attributes = RODATA.union(Attributes::DBM);
+    const DEVICE_LAZY: Attributes = MMIO_LAZY_MAP_FLAG;
+    const DEVICE: Attributes = DEVICE_LAZY.union(Attributes::VALID);
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Call {
+        MapRodata(MemoryRange),
+        MapDataDbm(MemoryRange),
+        MapDevice(MemoryRange),
+        MapDeviceLazy(MemoryRange),
+        ModifyRange(MemoryRange),
+        WalkRange(MemoryRange),
+    }
+
+    /// Records the calls made to it and keeps the resulting descriptors in an inactive `IdMap`.
+    struct MockPageTable {
+        idmap: IdMap,
+        calls: CallLog<Call>,
+    }
+
+    impl MockPageTable {
+        fn new() -> Self {
+            Self { idmap: IdMap::new(1, 1), calls: CallLog::default() }
+        }
+
+        fn record(&self, call: Call) {
+            self.calls.record(call)
+        }
+    }
+
+    fn to_range(range: &VaRange) -> MemoryRange {
+        range.start().0..range.end().0
+    }
+
+    impl MemoryMapper for MockPageTable {
+        fn map_rodata(&mut self, range: &VaRange) -> result::Result<(), MapError> {
+            self.record(Call::MapRodata(to_range(range)));
+            self.idmap.map_range(range, RODATA)
+        }
+
+        fn map_data_dbm(&mut self, range: &VaRange) -> result::Result<(), MapError> {
+            self.record(Call::MapDataDbm(to_range(range)));
+            self.idmap.map_range_with_constraints(range, DATA_DBM, Constraints::NO_BLOCK_MAPPINGS)
+        }
+
+        fn map_device(&mut self, range: &VaRange) -> result::Result<(), MapError> {
+            self.record(Call::MapDevice(to_range(range)));
+            self.idmap.map_range(range, DEVICE)
+        }
+
+        fn map_device_lazy(&mut self, range: &VaRange) -> result::Result<(), MapError> {
+            self.record(Call::MapDeviceLazy(to_range(range)));
+            self.idmap.map_range(range, DEVICE_LAZY)
+        }
+
+        fn modify_range<F>(&mut self, range: &VaRange, f: &F) -> result::Result<(), MapError>
+        where
+            F: Fn(&VaRange, &mut Descriptor, usize) -> result::Result<(), ()>,
+        {
+            self.record(Call::ModifyRange(to_range(range)));
+            self.idmap.modify_range(range, f)
+        }
+
+        fn walk_range<F>(&self, range: &VaRange, f: &F) -> result::Result<(), MapError>
+        where
+            F: Fn(&VaRange, &Descriptor, usize) -> result::Result<(), ()>,
+        {
+            self.record(Call::WalkRange(to_range(range)));
+            let mut callback = |mr: &VaRange, d: &Descriptor, l: usize| f(mr, d, l);
+            self.idmap.walk_range(range, &mut callback)
+        }
+    }
+
+    fn new_tracker() -> RegionTracker<MockPageTable> {
+        RegionTracker::new(MockPageTable::new(), TOTAL, MMIO, Some(PAYLOAD))
+    }
+
+    fn calls(tracker: &RegionTracker<MockPageTable>) -> Vec<Call> {
+        tracker.page_table.calls.to_vec()
+    }
+
+    fn pages(start: usize, count: usize) -> MemoryRange {
+        start..(start + count * PAGE_SIZE)
+    }
+
+    /// Clears the read-only flag of writable-clean PTEs, as a store would with hardware DBM.
+    fn mark_dirty(_: &VaRange, desc: &mut Descriptor, _: usize) -> result::Result<(), ()> {
+        let flags = desc.flags().ok_or(())?;
+        if !flags.contains(Attributes::DBM) {
+            return Err(());
+        }
+        desc.modify_flags(Attributes::empty(), Attributes::READ_ONLY);
+        Ok(())
+    }
+
+    /// Returns the flags of the PTEs of `range`, ignoring those set by the paging crate itself.
+    fn flags_of(tracker: &RegionTracker<MockPageTable>, range: &MemoryRange) -> Vec<Attributes> {
+        let mut flags = Vec::new();
+        tracker
+            .page_table
+            .idmap
+            .walk_range(&get_va_range(range), &mut |_: &VaRange, desc: &Descriptor, _: usize| {
+                flags.push(desc.flags().unwrap() & (DATA_DBM | DEVICE));
+                Ok(())
+            })
+            .unwrap();
+        flags
+    }
+
+    #[test]
+    #[should_panic]
+    fn mmio_overlapping_main_memory() {
+        RegionTracker::new(MockPageTable::new(), TOTAL, 0x0..(TOTAL.start + 1), None);
+    }
+
+    #[test]
+    fn alloc_maps_regions() {
+        let mut tracker = new_tracker();
+        let ro = pages(TOTAL.start, 2);
+        let rw = pages(ro.end, 4);
+
+        assert_eq!(tracker.alloc_range(&ro).unwrap(), ro);
+        assert_eq!(tracker.alloc_range_mut(&rw).unwrap(), rw);
+
+        assert_eq!(calls(&tracker), [Call::MapRodata(ro.clone()), Call::MapDataDbm(rw.clone())]);
+        assert!(tracker.is_allocated(&ro));
+        assert!(tracker.is_allocated(&pages(rw.start + PAGE_SIZE, 1)));
+        assert!(!tracker.is_allocated(&(ro.start..rw.end)));
+        assert!(flags_of(&tracker, &rw).iter().all(|&flags| flags == DATA_DBM));
+    }
+
+    #[test]
+    fn alloc_out_of_range() {
+        let mut tracker = new_tracker();
+
+        let result = tracker.alloc_range(&pages(TOTAL.end - PAGE_SIZE, 2));
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+        let result = tracker.alloc_range_mut(&pages(TOTAL.start - PAGE_SIZE, 2));
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+        assert!(calls(&tracker).is_empty());
+    }
+
+    #[test]
+    fn alloc_overlapping() {
+        let mut tracker = new_tracker();
+        tracker.alloc_range(&pages(TOTAL.start, 2)).unwrap();
+
+        let result = tracker.alloc_range_mut(&pages(TOTAL.start + PAGE_SIZE, 2));
+        assert!(matches!(result, Err(MemoryTrackerError::Overlaps)));
+        // Adjacent regions don't overlap.
+        tracker.alloc_range_mut(&pages(TOTAL.start + 2 * PAGE_SIZE, 2)).unwrap();
+        assert_eq!(calls(&tracker).len(), 2);
+    }
+
+    #[test]
+    fn alloc_full() {
+        let mut tracker = new_tracker();
+        for i in 0..CAPACITY {
+            tracker.alloc_range(&pages(TOTAL.start + i * PAGE_SIZE, 1)).unwrap();
+        }
+
+        let next = pages(TOTAL.end - PAGE_SIZE, 1);
+        assert!(matches!(tracker.alloc_range_mut(&next), Err(MemoryTrackerError::Full)));
+        assert_eq!(calls(&tracker).len(), CAPACITY);
+    }
+
+    #[test]
+    fn alloc_outside_main_memory() {
+        let mut tracker = new_tracker();
+        let outside = pages(TOTAL.end, 1);
+
+        // SAFETY: Nothing is actually mapped by the mock page table.
+        unsafe {
+            assert_eq!(tracker.alloc_range_outside_main_memory(&outside).unwrap(), outside);
+            let result = tracker.alloc_range_outside_main_memory(&outside);
+            assert!(matches!(result, Err(MemoryTrackerError::Overlaps)));
+        }
+        assert_eq!(calls(&tracker), [Call::MapRodata(outside)]);
+    }
+
+    #[test]
+    fn shrink() {
+        let mut tracker = new_tracker();
+        tracker.alloc_range(&pages(TOTAL.start + 4 * PAGE_SIZE, 1)).unwrap();
+
+        let result = tracker.shrink(&((TOTAL.start + PAGE_SIZE)..TOTAL.end));
+        assert!(matches!(result, Err(MemoryTrackerError::DifferentBaseAddress)));
+        let result = tracker.shrink(&(TOTAL.start..(TOTAL.end + PAGE_SIZE)));
+        assert!(matches!(result, Err(MemoryTrackerError::SizeTooLarge)));
+        let result = tracker.shrink(&pages(TOTAL.start, 4));
+        assert!(matches!(result, Err(MemoryTrackerError::SizeTooSmall)));
+
+        tracker.shrink(&pages(TOTAL.start, 5)).unwrap();
+        let result = tracker.alloc_range(&pages(TOTAL.start + 5 * PAGE_SIZE, 1));
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+    }
+
+    #[test]
+    fn map_mmio_range_eagerly() {
+        let mut tracker = new_tracker();
+        let range = pages(0x1000_0000, 2);
+
+        tracker.map_mmio_range(range.clone(), false).unwrap();
+
+        assert_eq!(calls(&tracker), [Call::MapDevice(range.clone())]);
+        assert!(flags_of(&tracker, &range).iter().all(|&flags| flags == DEVICE));
+        // Valid mappings are never lazily mapped again.
+        let result = tracker.map_lazy_mmio_as_valid(&get_va_range(&pages(range.start, 1)));
+        assert!(matches!(result, Err(MemoryTrackerError::InvalidPte)));
+    }
+
+    #[test]
+    fn map_mmio_range_lazily() {
+        let mut tracker = new_tracker();
+        let range = pages(0x1000_0000, 2);
+
+        tracker.map_mmio_range(range.clone(), true).unwrap();
+        assert_eq!(calls(&tracker), [Call::MapDeviceLazy(range.clone())]);
+        assert!(flags_of(&tracker, &range).iter().all(|&flags| flags == DEVICE_LAZY));
+
+        // Only the faulting page becomes valid, and only once.
+        let page = pages(range.start + PAGE_SIZE, 1);
+        tracker.map_lazy_mmio_as_valid(&get_va_range(&page)).unwrap();
+        assert_eq!(flags_of(&tracker, &page), [DEVICE]);
+        assert_eq!(flags_of(&tracker, &pages(range.start, 1)), [DEVICE_LAZY]);
+        let result = tracker.map_lazy_mmio_as_valid(&get_va_range(&page));
+        assert!(matches!(result, Err(MemoryTrackerError::InvalidPte)));
+    }
+
+    #[test]
+    fn map_mmio_range_errors() {
+        let mut tracker = new_tracker();
+        tracker.map_mmio_range(pages(0x1000_0000, 2), false).unwrap();
+
+        let result = tracker.map_mmio_range(pages(MMIO.end - PAGE_SIZE, 2), false);
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+        let result = tracker.map_mmio_range(pages(TOTAL.start, 1), true);
+        assert!(matches!(result, Err(MemoryTrackerError::OutOfRange)));
+        let result = tracker.map_mmio_range(pages(0x1000_1000, 2), true);
+        assert!(matches!(result, Err(MemoryTrackerError::Overlaps)));
+
+        for i in 1..MMIO_CAPACITY {
+            tracker.map_mmio_range(pages(0x2000_0000 + i * PAGE_SIZE, 1), i % 2 == 0).unwrap();
+        }
+        let result = tracker.map_mmio_range(pages(0x3000_0000, 1), false);
+        assert!(matches!(result, Err(MemoryTrackerError::Full)));
+        assert_eq!(calls(&tracker).len(), MMIO_CAPACITY);
+    }
+
+    #[test]
+    fn flush_dirty_pages_walks_writable_regions() {
+        let mut tracker = new_tracker();
+        let rw1 = pages(TOTAL.start, 2);
+        let ro = pages(rw1.end, 2);
+        let rw2 = pages(ro.end, 2);
+        tracker.alloc_range_mut(&rw1).unwrap();
+        tracker.alloc_range(&ro).unwrap();
+        tracker.alloc_range_mut(&rw2).unwrap();
+        tracker.page_table.calls.take();
+
+        tracker.flush_dirty_pages(&|_: &VaRange, _: &Descriptor, _: usize| Ok(())).unwrap();
+
+        let expected = [Call::WalkRange(rw1), Call::WalkRange(rw2), Call::WalkRange(PAYLOAD)];
+        assert_eq!(calls(&tracker), expected);
+    }
+
+    #[test]
+    fn flush_dirty_pages_without_payload() {
+        let mut tracker = RegionTracker::new(MockPageTable::new(), TOTAL, MMIO, None);
+        tracker.alloc_range(&pages(TOTAL.start, 2)).unwrap();
+        tracker.page_table.calls.take();
+
+        tracker.flush_dirty_pages(&|_: &VaRange, _: &Descriptor, _: usize| Ok(())).unwrap();
+
+        assert!(calls(&tracker).is_empty());
+    }
+
+    #[test]
+    fn flush_dirty_pages_finds_dirty_pages() {
+        let mut tracker = RegionTracker::new(MockPageTable::new(), TOTAL, MMIO, None);
+        let rw = pages(TOTAL.start, 4);
+        tracker.alloc_range_mut(&rw).unwrap();
+        tracker.alloc_range(&pages(rw.end, 1)).unwrap();
+
+        let dirty = pages(rw.start + 2 * PAGE_SIZE, 1);
+        tracker.handle_permission_fault(VirtualAddress(dirty.start + 8), &mark_dirty).unwrap();
+        assert_eq!(
+            calls(&tracker).last(),
+            Some(&Call::ModifyRange((dirty.start + 8)..(dirty.start + 9)))
+        );
+        // Permission faults on pages without dirty state management can't be handled.
+        let result = tracker.handle_permission_fault(VirtualAddress(rw.end), &mark_dirty);
+        assert!(matches!(result, Err(MemoryTrackerError::SetPteDirtyFailed)));
+
+        let flushed = RefCell::new(Vec::new());
+        tracker
+            .flush_dirty_pages(&|range: &VaRange, desc: &Descriptor, _: usize| {
+                if !desc.flags().ok_or(())?.contains(Attributes::READ_ONLY) {
+                    flushed.borrow_mut().push(to_range(range));
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(flushed.into_inner(), [dirty]);
+    }
+
+    #[test]
+    fn flush_dirty_pages_error() {
+        let mut tracker = new_tracker();
+        tracker.alloc_range_mut(&pages(TOTAL.start, 1)).unwrap();
+
+        let result = tracker.flush_dirty_pages(&|_: &VaRange, _: &Descriptor, _: usize| Err(()));
+        assert!(matches!(result, Err(MemoryTrackerError::FlushRegionFailed)));
+    }
+}
@@ -15,7 +15,9 @@
 //! HAL for the virtio_drivers crate.
 
 use super::pci::PCI_INFO;
-use crate::memory::{alloc_shared, dealloc_shared, phys_to_virt, virt_to_phys};
+use crate::memory::{
+    alloc_shared, dealloc_shared, debug_assert_shared, phys_to_virt, virt_to_phys,
+};
 use crate::util::RangeExt as _;
 use core::alloc::Layout;
 use core::mem::size_of;
@@ -49,6 +51,7 @@ unsafe impl Hal for HalImpl {
         // SAFETY: vaddr points to a region allocated for the caller so is safe to access.
         unsafe { core::ptr::write_bytes(vaddr.as_ptr(), 0, layout.size()) };
         let paddr = virt_to_phys(vaddr);
+        debug_assert_shared(&(paddr..(paddr + layout.size())));
         (paddr, vaddr)
     }
 
@@ -91,6 +94,7 @@ unsafe impl Hal for HalImpl {
         let bounce = alloc_shared(bb_layout(size))
             .expect("Failed to allocate and share VirtIO bounce buffer with host");
         let paddr = virt_to_phys(bounce);
+        debug_assert_shared(&(paddr..(paddr + size)));
         if direction != BufferDirection::DeviceToDriver {
             let src = buffer.cast::<u8>().as_ptr().cast_const();
             trace!("VirtIO bounce buffer at {bounce:?} (PA:{paddr:#x}) initialized from {src:?}");